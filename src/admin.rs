@@ -0,0 +1,373 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use dav_server::body::Body;
+use http_body_util::{BodyExt, Limited};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+use crate::vfs::RootFileSystem;
+use crate::webdav::Connections;
+
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Runtime control API served on its own address, requires `Authorization: Bearer <token>`
+#[derive(Clone)]
+pub struct AdminServer {
+    pub token: String,
+    pub fs: RootFileSystem,
+    pub connections: Connections,
+    /// Global read-only switch shared with the file systems
+    pub read_only: Arc<AtomicBool>,
+    /// The effective configuration at startup with secrets redacted, only `read_only` follows
+    /// later changes, a cookie rotated with `PUT /cookie` is not reflected
+    pub config: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct InvalidateRequest {
+    path: String,
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Deserialize)]
+struct CookieRequest {
+    cookie: String,
+    /// Required when serving multiple accounts
+    #[serde(default)]
+    account: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReadOnlyRequest {
+    read_only: bool,
+}
+
+#[derive(Serialize)]
+struct ConnectionResponse {
+    id: u64,
+    remote_addr: SocketAddr,
+    connected_secs: u64,
+}
+
+impl AdminServer {
+    /// Bind `addr` and serve in the background
+    pub async fn start(self, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to listen on {}", addr))?;
+        info!("serving admin API on http://{}", addr);
+        tokio::spawn(async move {
+            loop {
+                let (stream, remote_addr) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        error!(error = %err, "admin API accept failed");
+                        continue;
+                    }
+                };
+                let admin = self.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| {
+                        let admin = admin.clone();
+                        async move { Ok::<_, Infallible>(admin.handle(req, remote_addr).await) }
+                    });
+                    let builder = auto::Builder::new(TokioExecutor::new());
+                    if let Err(e) = builder.serve_connection(TokioIo::new(stream), service).await {
+                        error!("admin API serve error: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    async fn handle<B>(&self, req: Request<B>, remote_addr: SocketAddr) -> Response<Body>
+    where
+        B: hyper::body::Body,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
+        if !self.authorized(&req) {
+            warn!(remote_addr = %remote_addr, "unauthorized admin API request");
+            return text(StatusCode::UNAUTHORIZED, "Authentication required");
+        }
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        info!(method = %method, path = %path, remote_addr = %remote_addr, "admin API request");
+        match (method, path.as_str()) {
+            (Method::GET, "/config") => {
+                let mut config = self.config.clone();
+                config["read_only"] = json!(self.read_only.load(Ordering::Relaxed));
+                json_response(&config)
+            }
+            (Method::GET, "/connections") => {
+                let now = SystemTime::now();
+                let connections: Vec<_> = self
+                    .connections
+                    .list()
+                    .into_iter()
+                    .map(|conn| ConnectionResponse {
+                        id: conn.id,
+                        remote_addr: conn.remote_addr,
+                        connected_secs: now.duration_since(conn.connected_at).unwrap_or_default().as_secs(),
+                    })
+                    .collect();
+                json_response(&connections)
+            }
+            (Method::GET, "/uploads") => json_response(&self.fs.uploads()),
+            (Method::POST, "/cache/invalidate") => {
+                let req: InvalidateRequest = match read_json(req).await {
+                    Ok(req) => req,
+                    Err(res) => return res,
+                };
+                match self.fs.invalidate(&req.path, req.recursive).await {
+                    Ok(()) => {
+                        info!(path = %req.path, recursive = req.recursive, "cache invalidated");
+                        json_response(&json!({ "path": req.path, "recursive": req.recursive }))
+                    }
+                    Err(err) => text(StatusCode::NOT_FOUND, &err.to_string()),
+                }
+            }
+            (Method::PUT, "/cookie") => {
+                let req: CookieRequest = match read_json(req).await {
+                    Ok(req) => req,
+                    Err(res) => return res,
+                };
+                let Some(drive) = self.fs.drive(req.account.as_deref()) else {
+                    return text(StatusCode::NOT_FOUND, "unknown account");
+                };
+                if let Err(err) = drive.set_cookie(&req.cookie) {
+                    return text(StatusCode::BAD_REQUEST, &err.to_string());
+                }
+                // the new cookie is kept either way, report whether it works
+                match drive.check_session().await {
+                    Ok(()) => json_response(&json!({ "valid": true })),
+                    Err(err) => json_response(&json!({ "valid": false, "error": err.to_string() })),
+                }
+            }
+            (Method::PUT, "/read-only") => {
+                let req: ReadOnlyRequest = match read_json(req).await {
+                    Ok(req) => req,
+                    Err(res) => return res,
+                };
+                self.read_only.store(req.read_only, Ordering::Relaxed);
+                info!(read_only = req.read_only, "read-only mode switched");
+                json_response(&json!({ "read_only": req.read_only }))
+            }
+            (_, "/config" | "/connections" | "/uploads" | "/cache/invalidate" | "/cookie" | "/read-only") => {
+                text(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed")
+            }
+            _ => text(StatusCode::NOT_FOUND, "Not Found"),
+        }
+    }
+
+    fn authorized<B>(&self, req: &Request<B>) -> bool {
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            // compare digests, comparing the tokens themselves would leak their length
+            .is_some_and(|token| {
+                Sha256::digest(token.trim().as_bytes())
+                    .ct_eq(&Sha256::digest(self.token.as_bytes()))
+                    .into()
+            })
+    }
+}
+
+async fn read_json<T, B>(req: Request<B>) -> Result<T, Response<Body>>
+where
+    T: DeserializeOwned,
+    B: hyper::body::Body,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    let body = match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => return Err(text(StatusCode::BAD_REQUEST, &err.to_string())),
+    };
+    serde_json::from_slice(&body).map_err(|err| text(StatusCode::BAD_REQUEST, &format!("invalid request: {}", err)))
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_string_pretty(value) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(err) => text(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+fn text(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bytes::Bytes;
+    use dav_server::memls::MemLs;
+    use dav_server::DavHandler;
+    use http_body_util::Full;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::drive::mock::MockQuark;
+    use crate::vfs::{AccountsFileSystem, QuarkDriveFileSystem};
+
+    const TOKEN: &str = "admin-token";
+
+    fn new_admin(accounts: &[(&str, &MockQuark)]) -> AdminServer {
+        let read_only = Arc::new(AtomicBool::new(false));
+        let accounts = accounts
+            .iter()
+            .map(|(name, mock)| {
+                let mut fs = QuarkDriveFileSystem::new(mock.drive(), "/".to_string(), 1000, 600).unwrap();
+                fs.set_read_only_switch(read_only.clone());
+                (name.to_string(), fs)
+            })
+            .collect::<BTreeMap<_, _>>();
+        AdminServer {
+            token: TOKEN.to_string(),
+            fs: RootFileSystem::Accounts(AccountsFileSystem::new(accounts)),
+            connections: Connections::default(),
+            read_only,
+            config: json!({ "read_only": false }),
+        }
+    }
+
+    async fn call(admin: &AdminServer, method: Method, path: &str, token: Option<&str>, body: Value) -> (StatusCode, String) {
+        let mut req = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let req = req.body(Full::new(Bytes::from(body.to_string()))).unwrap();
+        let res = admin.handle(req, "127.0.0.1:1234".parse().unwrap()).await;
+        let status = res.status();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    async fn dav_send(dav: &DavHandler, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> (StatusCode, String) {
+        let mut req = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let res = dav.handle(req.body(Full::new(Bytes::copy_from_slice(body))).unwrap()).await;
+        let status = res.status();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    fn dav(fs: Box<dyn dav_server::fs::GuardedFileSystem<()>>) -> DavHandler {
+        DavHandler::builder().filesystem(fs).locksystem(MemLs::new()).build_handler()
+    }
+
+    #[tokio::test]
+    async fn test_authorization() {
+        let mock = MockQuark::start().await;
+        let admin = new_admin(&[("alice", &mock)]);
+        for token in [None, Some("wrong"), Some("admin-token-but-longer"), Some("")] {
+            let (status, _) = call(&admin, Method::GET, "/config", token, Value::Null).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{:?}", token);
+        }
+        let (status, _) = call(&admin, Method::GET, "/config", Some(TOKEN), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&admin, Method::GET, "/nothing", Some(TOKEN), Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_account() {
+        let alice = MockQuark::start().await;
+        let bob = MockQuark::start().await;
+        let admin = new_admin(&[("alice", &alice), ("bob", &bob)]);
+        let dav = dav(admin.fs.clone().boxed());
+        let list = async |path| dav_send(&dav, "PROPFIND", path, &[("Depth", "1")], b"").await.1;
+
+        assert!(!list("/alice/").await.contains("a.txt"));
+        assert!(!list("/bob/").await.contains("b.txt"));
+        alice.add_file("0", "a.txt", b"a");
+        bob.add_file("0", "b.txt", b"b");
+        // the listings are cached
+        assert!(!list("/alice/").await.contains("a.txt"));
+
+        let (status, _) = call(&admin, Method::POST, "/cache/invalidate", Some(TOKEN), json!({ "path": "/alice/" })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(list("/alice/").await.contains("a.txt"));
+        assert!(!list("/bob/").await.contains("b.txt"));
+
+        let (status, _) = call(&admin, Method::POST, "/cache/invalidate", Some(TOKEN), json!({ "path": "/", "recursive": true })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(list("/bob/").await.contains("b.txt"));
+
+        let (status, body) = call(&admin, Method::POST, "/cache/invalidate", Some(TOKEN), json!({ "path": "/carol/" })).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_switch() {
+        let mock = MockQuark::start().await;
+        let admin = new_admin(&[("alice", &mock)]);
+        let dav = dav(admin.fs.scoped("/alice", false).unwrap());
+        let put = async |path| dav_send(&dav, "PUT", path, &[("Content-Length", "1")], b"x").await.0;
+        assert_eq!(put("/a.txt").await, StatusCode::CREATED);
+
+        let (status, _) = call(&admin, Method::PUT, "/read-only", Some(TOKEN), json!({ "read_only": true })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(put("/b.txt").await, StatusCode::FORBIDDEN);
+        let (_, body) = call(&admin, Method::GET, "/config", Some(TOKEN), Value::Null).await;
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["read_only"], json!(true));
+
+        call(&admin, Method::PUT, "/read-only", Some(TOKEN), json!({ "read_only": false })).await;
+        assert_eq!(put("/b.txt").await, StatusCode::CREATED);
+        assert_eq!(mock.list("/"), ["a.txt", "b.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_cookie() {
+        let alice = MockQuark::start().await;
+        let bob = MockQuark::start().await;
+        let admin = new_admin(&[("alice", &alice), ("bob", &bob)]);
+
+        let (status, body) = call(&admin, Method::PUT, "/cookie", Some(TOKEN), json!({ "cookie": "__pus=rotated" })).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+
+        let (status, body) = call(
+            &admin,
+            Method::PUT,
+            "/cookie",
+            Some(TOKEN),
+            json!({ "cookie": "__pus=rotated", "account": "bob" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["valid"], json!(true));
+        assert_eq!(bob.cookies().last().unwrap(), "__pus=rotated");
+        assert!(alice.cookies().iter().all(|cookie| cookie != "__pus=rotated"));
+
+        let (status, _) = call(&admin, Method::GET, "/cookie", Some(TOKEN), Value::Null).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
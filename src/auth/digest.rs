@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::debug;
+
+const NONCE_TTL: Duration = Duration::from_secs(300);
+const MAX_NONCES: usize = 10000;
+
+#[derive(Debug, Clone, Copy)]
+struct NonceState {
+    last_used: Instant,
+    /// Highest nonce count seen, to reject replayed requests
+    nc: u32,
+}
+
+/// Result of checking a Digest `Authorization` header
+#[derive(Debug, PartialEq, Eq)]
+pub enum DigestOutcome {
+    Authenticated(String),
+    /// The nonce is unknown, expired or its count was already used, the client should retry with a new one
+    Stale,
+    Invalid,
+}
+
+/// RFC 7616 HTTP Digest authentication
+///
+/// Nonces are a timestamp signed with a random key of this process, so handing them out costs nothing.
+/// Nonce counts are only tracked once a client authenticated, for at most `MAX_NONCES` nonces.
+#[derive(Debug, Clone)]
+pub struct DigestAuth {
+    realm: String,
+    key: Arc<[u8; 32]>,
+    nonces: Arc<DashMap<String, NonceState>>,
+}
+
+impl DigestAuth {
+    pub fn new(realm: &str) -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self {
+            realm: realm.to_string(),
+            key: Arc::new(key),
+            nonces: Arc::new(DashMap::new()),
+        }
+    }
+
+    fn sign(&self, timestamp: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_slice()).expect("HMAC takes keys of any size");
+        mac.update(timestamp.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn new_nonce(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let timestamp = format!("{:x}", now.as_secs());
+        format!("{}.{}", timestamp, self.sign(&timestamp))
+    }
+
+    /// Whether the nonce was issued by this process within `NONCE_TTL`
+    fn is_fresh(&self, nonce: &str) -> bool {
+        let Some((timestamp, signature)) = nonce.split_once('.') else {
+            return false;
+        };
+        if !bool::from(self.sign(timestamp).as_bytes().ct_eq(signature.as_bytes())) {
+            return false;
+        }
+        let Ok(issued) = u64::from_str_radix(timestamp, 16) else {
+            return false;
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        now.as_secs().saturating_sub(issued) < NONCE_TTL.as_secs()
+    }
+
+    /// Remember the nonce count of an authenticated request, false if it was already used
+    fn use_nonce_count(&self, nonce: &str, nc: u32) -> bool {
+        if !self.nonces.contains_key(nonce) && self.nonces.len() >= MAX_NONCES {
+            self.nonces.retain(|_, state| state.last_used.elapsed() < NONCE_TTL);
+            // still full, make room by forgetting the least recently used nonce
+            if self.nonces.len() >= MAX_NONCES {
+                let oldest = self
+                    .nonces
+                    .iter()
+                    .min_by_key(|entry| entry.value().last_used)
+                    .map(|entry| entry.key().clone());
+                if let Some(oldest) = oldest {
+                    self.nonces.remove(&oldest);
+                }
+            }
+        }
+        let mut state = self.nonces.entry(nonce.to_string()).or_insert(NonceState {
+            last_used: Instant::now(),
+            nc: 0,
+        });
+        if nc <= state.nc {
+            return false;
+        }
+        state.nc = nc;
+        state.last_used = Instant::now();
+        true
+    }
+
+    /// `WWW-Authenticate` header values, SHA-256 first and MD5 for older clients
+    pub fn challenges(&self, stale: bool) -> Vec<String> {
+        let nonce = self.new_nonce();
+        ["SHA-256", "MD5"]
+            .iter()
+            .map(|algorithm| {
+                format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\", opaque=\"{}\"{}",
+                    self.realm,
+                    algorithm,
+                    nonce,
+                    hash(algorithm, &self.realm),
+                    if stale { ", stale=true" } else { "" }
+                )
+            })
+            .collect()
+    }
+
+    /// Check a Digest `Authorization` header, `password_of` returns the plain text password of a user
+    pub fn verify<F>(&self, header: &str, method: &str, request_uri: &str, password_of: F) -> DigestOutcome
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let Some(params) = header.strip_prefix("Digest ").map(parse_params) else {
+            return DigestOutcome::Invalid;
+        };
+        let get = |key: &str| params.get(key).map(String::as_str);
+        let (Some(username), Some(realm), Some(nonce), Some(uri), Some(response)) = (
+            get("username"),
+            get("realm"),
+            get("nonce"),
+            get("uri"),
+            get("response"),
+        ) else {
+            return DigestOutcome::Invalid;
+        };
+        let algorithm = get("algorithm").unwrap_or("MD5");
+        if !["MD5", "SHA-256"].contains(&algorithm) || realm != self.realm {
+            return DigestOutcome::Invalid;
+        }
+        // some clients send the absolute URI
+        if uri != request_uri && !(uri.contains("://") && uri.ends_with(request_uri)) {
+            debug!(uri = %uri, request_uri = %request_uri, "digest uri mismatch");
+            return DigestOutcome::Invalid;
+        }
+        let Some(password) = password_of(username) else {
+            return DigestOutcome::Invalid;
+        };
+
+        let ha1 = hash(algorithm, &format!("{}:{}:{}", username, realm, password));
+        let ha2 = hash(algorithm, &format!("{}:{}", method, uri));
+        let (expected, nc) = match get("qop") {
+            Some("auth") => {
+                let (Some(nc), Some(cnonce)) = (get("nc"), get("cnonce")) else {
+                    return DigestOutcome::Invalid;
+                };
+                let Ok(nc_value) = u32::from_str_radix(nc, 16) else {
+                    return DigestOutcome::Invalid;
+                };
+                let expected = hash(
+                    algorithm,
+                    &format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2),
+                );
+                (expected, nc_value)
+            }
+            // RFC 2069 clients send no nonce count, their nonces are good for a single request
+            None => (hash(algorithm, &format!("{}:{}:{}", ha1, nonce, ha2)), u32::MAX),
+            Some(_) => return DigestOutcome::Invalid,
+        };
+        if !bool::from(expected.as_bytes().ct_eq(response.as_bytes())) {
+            return DigestOutcome::Invalid;
+        }
+
+        // the credentials are right, now make sure the nonce is fresh and not replayed
+        if !self.is_fresh(nonce) {
+            return DigestOutcome::Stale;
+        }
+        // concurrent requests may arrive out of order, not a wrong password
+        if !self.use_nonce_count(nonce, nc) {
+            debug!(username = %username, nc = nc, "digest nonce count replayed");
+            return DigestOutcome::Stale;
+        }
+        DigestOutcome::Authenticated(username.to_string())
+    }
+}
+
+fn hash(algorithm: &str, data: &str) -> String {
+    if algorithm == "SHA-256" {
+        format!("{:x}", Sha256::digest(data.as_bytes()))
+    } else {
+        format!("{:x}", Md5::digest(data.as_bytes()))
+    }
+}
+
+/// Parse comma separated `key=value` or `key="value"` pairs
+fn parse_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let Some((key, after)) = rest.split_once('=') else {
+            break;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let after = after.trim_start();
+        let (value, remaining) = if let Some(quoted) = after.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            value.push(escaped);
+                        }
+                    }
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    _ => value.push(c),
+                }
+            }
+            (value, &quoted[end..])
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (after[..end].trim().to_string(), &after[end..])
+        };
+        params.insert(key, value);
+        rest = remaining.trim_start().trim_start_matches(',').trim_start();
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nonce_of(challenge: &str) -> String {
+        parse_params(challenge.strip_prefix("Digest ").unwrap())["nonce"].clone()
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        let auth = DigestAuth::new("quarkdriver-webdav");
+        let nonce = nonce_of(&auth.challenges(false)[0]);
+        let password_of = |user: &str| (user == "admin").then(|| "secret".to_string());
+
+        let ha1 = hash("SHA-256", "admin:quarkdriver-webdav:secret");
+        let ha2 = hash("SHA-256", "PROPFIND:/movies/");
+        let response = hash(
+            "SHA-256",
+            &format!("{}:{}:00000001:abcdef:auth:{}", ha1, nonce, ha2),
+        );
+        let header = format!(
+            "Digest username=\"admin\", realm=\"quarkdriver-webdav\", nonce=\"{}\", uri=\"/movies/\", \
+             algorithm=SHA-256, response=\"{}\", qop=auth, nc=00000001, cnonce=\"abcdef\"",
+            nonce, response
+        );
+        assert_eq!(
+            auth.verify(&header, "PROPFIND", "/movies/", password_of),
+            DigestOutcome::Authenticated("admin".to_string())
+        );
+        // replayed nonce count
+        assert_eq!(
+            auth.verify(&header, "PROPFIND", "/movies/", password_of),
+            DigestOutcome::Stale
+        );
+        // wrong method
+        assert_eq!(
+            auth.verify(&header, "DELETE", "/movies/", password_of),
+            DigestOutcome::Invalid
+        );
+        // tampered nonce
+        let header = header.replace(&nonce, "0123");
+        assert_eq!(
+            auth.verify(&header, "PROPFIND", "/movies/", password_of),
+            DigestOutcome::Invalid
+        );
+    }
+
+    #[test]
+    fn test_replay_without_qop() {
+        let auth = DigestAuth::new("quarkdriver-webdav");
+        let nonce = nonce_of(&auth.challenges(false)[0]);
+        let password_of = |user: &str| (user == "admin").then(|| "secret".to_string());
+
+        let ha1 = hash("MD5", "admin:quarkdriver-webdav:secret");
+        let ha2 = hash("MD5", "GET:/a.txt");
+        let header = format!(
+            "Digest username=\"admin\", realm=\"quarkdriver-webdav\", nonce=\"{}\", uri=\"/a.txt\", response=\"{}\"",
+            nonce,
+            hash("MD5", &format!("{}:{}:{}", ha1, nonce, ha2))
+        );
+        assert_eq!(
+            auth.verify(&header, "GET", "/a.txt", password_of),
+            DigestOutcome::Authenticated("admin".to_string())
+        );
+        // a captured request can't be replayed
+        assert_eq!(auth.verify(&header, "GET", "/a.txt", password_of), DigestOutcome::Stale);
+
+        // nor can the nonce be reused with a nonce count
+        let response = hash("MD5", &format!("{}:{}:00000002:abcdef:auth:{}", ha1, nonce, ha2));
+        let header = format!(
+            "Digest username=\"admin\", realm=\"quarkdriver-webdav\", nonce=\"{}\", uri=\"/a.txt\", \
+             response=\"{}\", qop=auth, nc=00000002, cnonce=\"abcdef\"",
+            nonce, response
+        );
+        assert_eq!(auth.verify(&header, "GET", "/a.txt", password_of), DigestOutcome::Stale);
+    }
+
+    #[test]
+    fn test_nonces() {
+        let auth = DigestAuth::new("quarkdriver-webdav");
+        // handing out nonces keeps no state
+        for _ in 0..100 {
+            auth.challenges(false);
+        }
+        assert!(auth.nonces.is_empty());
+
+        let nonce = nonce_of(&auth.challenges(false)[0]);
+        assert!(auth.is_fresh(&nonce));
+        assert!(!DigestAuth::new("quarkdriver-webdav").is_fresh(&nonce));
+        let (_, signature) = nonce.split_once('.').unwrap();
+        let expired = format!("{:x}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - 301);
+        assert!(!auth.is_fresh(&format!("{}.{}", expired, auth.sign(&expired))));
+        assert!(!auth.is_fresh(&format!("{}.{}", expired, signature)));
+
+        assert!(auth.use_nonce_count(&nonce, 1));
+        assert!(auth.use_nonce_count(&nonce, 3));
+        assert!(!auth.use_nonce_count(&nonce, 2));
+
+        // the tracked nonces are capped, the least recently used goes first
+        for i in 0..MAX_NONCES {
+            assert!(auth.use_nonce_count(&format!("nonce-{}", i), 1));
+        }
+        assert_eq!(auth.nonces.len(), MAX_NONCES);
+        assert!(!auth.nonces.contains_key(&nonce));
+        assert!(auth.nonces.contains_key(&format!("nonce-{}", MAX_NONCES - 1)));
+    }
+}
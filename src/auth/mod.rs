@@ -0,0 +1,554 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Argon2, Params};
+use clap::ValueEnum;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use hyper::HeaderMap;
+use moka::future::Cache as MokaCache;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+use tracing::{error, warn};
+
+pub mod digest;
+
+pub use digest::DigestAuth;
+
+/// A WebDAV account from the users file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub username: String,
+    /// Plain text password, or an argon2/bcrypt hash
+    pub password: String,
+    /// Root directory path of this user
+    #[serde(default = "default_root")]
+    pub root: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+fn default_root() -> String {
+    "/".to_string()
+}
+
+/// Load users from a JSON file containing an array of users
+pub fn load_users(path: &Path) -> Result<Vec<UserConfig>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read users file {}", path.display()))?;
+    let users: Vec<UserConfig> = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse users file {}", path.display()))?;
+    for (i, user) in users.iter().enumerate() {
+        if users[..i].iter().any(|u| u.username == user.username) {
+            anyhow::bail!("duplicate user {} in users file", user.username);
+        }
+    }
+    Ok(users)
+}
+
+/// A configured password, either in plain text or as an argon2/bcrypt hash
+#[derive(Debug, Clone)]
+pub enum Password {
+    Plain(String),
+    Argon2(String),
+    Bcrypt(String),
+}
+
+impl Password {
+    pub fn parse(password: &str) -> Self {
+        if password.starts_with("$argon2") {
+            Password::Argon2(password.to_string())
+        } else if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| password.starts_with(prefix))
+        {
+            Password::Bcrypt(password.to_string())
+        } else {
+            Password::Plain(password.to_string())
+        }
+    }
+
+    /// The password in plain text, not available for hashed passwords
+    pub fn plain(&self) -> Option<&str> {
+        match self {
+            Password::Plain(password) => Some(password),
+            _ => None,
+        }
+    }
+
+    /// Check a password in constant time
+    pub fn verify(&self, password: &str) -> bool {
+        match self {
+            Password::Plain(expected) => expected.as_bytes().ct_eq(password.as_bytes()).into(),
+            Password::Argon2(hash) => match PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(err) => {
+                    error!(error = %err, "invalid argon2 password hash");
+                    false
+                }
+            },
+            Password::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or_else(|err| {
+                error!(error = %err, "invalid bcrypt password hash");
+                false
+            }),
+        }
+    }
+
+    /// Check a password on the blocking thread pool, argon2 and bcrypt take a while
+    pub async fn verify_async(&self, password: &str) -> bool {
+        if let Password::Plain(_) = self {
+            return self.verify(password);
+        }
+        let this = self.clone();
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || this.verify(&password))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// A random password hashed with the same algorithm and cost,
+    /// unknown users are checked against it so they take as long as wrong passwords
+    pub fn dummy(&self) -> Password {
+        let random = Uuid::new_v4().to_string();
+        match self {
+            Password::Plain(_) => Password::Plain(random),
+            Password::Argon2(hash) => {
+                let dummy = PasswordHash::new(hash).ok().and_then(|hash| {
+                    let params = Params::try_from(&hash).ok()?;
+                    let salt = SaltString::generate(&mut OsRng);
+                    Argon2::default()
+                        .hash_password_customized(random.as_bytes(), Some(hash.algorithm), hash.version, params, &salt)
+                        .ok()
+                        .map(|hash| hash.to_string())
+                });
+                // the configured hash is as slow and nobody gets in as an unknown user anyway
+                Password::Argon2(dummy.unwrap_or_else(|| hash.clone()))
+            }
+            Password::Bcrypt(hash) => {
+                let cost = hash
+                    .get(4..6)
+                    .and_then(|cost| cost.parse().ok())
+                    .unwrap_or(bcrypt::DEFAULT_COST);
+                Password::Bcrypt(bcrypt::hash(&random, cost).unwrap_or_else(|_| hash.clone()))
+            }
+        }
+    }
+}
+
+/// Successful password checks, remembered for a while since hashes are slow to verify
+///
+/// Entries are keyed by an HMAC of the credentials with a random key of this process,
+/// so the cache never holds anything a password could be recovered from.
+#[derive(Clone)]
+pub struct VerifiedCache {
+    key: Arc<[u8; 32]>,
+    verified: MokaCache<String, ()>,
+}
+
+impl VerifiedCache {
+    pub fn new(ttl: Duration) -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self {
+            key: Arc::new(key),
+            verified: MokaCache::builder().max_capacity(1000).time_to_live(ttl).build(),
+        }
+    }
+
+    fn cache_key(&self, username: &str, password: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_slice()).expect("HMAC takes keys of any size");
+        mac.update(username.as_bytes());
+        mac.update(b"\0");
+        mac.update(password.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Check the password of a user, answering from the cache when it was right before
+    pub async fn verify(&self, expected: &Password, username: &str, password: &str) -> bool {
+        let key = self.cache_key(username, password);
+        if self.verified.contains_key(&key) {
+            return true;
+        }
+        if expected.verify_async(password).await {
+            self.verified.insert(key, ()).await;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// HTTP authentication scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthScheme {
+    Basic,
+    /// RFC 7616 Digest, requires plain text passwords
+    Digest,
+}
+
+/// Hash a password with argon2 or bcrypt
+pub fn hash_password(password: &str, algorithm: HashAlgorithm) -> Result<String> {
+    match algorithm {
+        HashAlgorithm::Argon2 => {
+            let salt = SaltString::generate(&mut OsRng);
+            let hash = Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map_err(|err| anyhow::anyhow!("failed to hash password: {}", err))?;
+            Ok(hash.to_string())
+        }
+        HashAlgorithm::Bcrypt => Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?),
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum HashAlgorithm {
+    Argon2,
+    Bcrypt,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoginGuardConfig {
+    /// Failed logins before an address is banned
+    pub max_failures: u32,
+    /// Back-off after the first failure, doubled for every further failure
+    pub backoff: Duration,
+    pub ban: Duration,
+    /// Reverse proxies that name the client in `X-Forwarded-For` or `Forwarded`, never banned themselves
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LoginFailures {
+    count: u32,
+    /// Attempts still being verified, counted against `max_failures` until they finish
+    pending: u32,
+    last: Instant,
+}
+
+/// Per-IP failed login tracking with exponential back-off and temporary bans
+#[derive(Debug, Clone)]
+pub struct LoginGuard {
+    config: LoginGuardConfig,
+    failures: Arc<DashMap<IpAddr, LoginFailures>>,
+}
+
+impl LoginGuard {
+    pub fn new(config: LoginGuardConfig) -> Self {
+        Self {
+            config,
+            failures: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// The address of the client, named by the forwarding headers when the peer is a trusted proxy
+    ///
+    /// Returns `None` when a trusted proxy doesn't name a client, so the proxy itself is never tracked.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> Option<IpAddr> {
+        if !self.config.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+        // the nearest hop not added by one of our proxies, anything before it may be forged
+        forwarded_for(headers)
+            .into_iter()
+            .rev()
+            .find(|ip| ip.is_none_or(|ip| !self.config.trusted_proxies.contains(&ip)))
+            .flatten()
+    }
+
+    fn blocked_for(&self, failures: &LoginFailures) -> Duration {
+        if failures.count == 0 {
+            return Duration::ZERO;
+        }
+        let wait = if failures.count >= self.config.max_failures {
+            self.config.ban
+        } else {
+            let exp = failures.count.saturating_sub(1).min(31);
+            self.config.backoff.saturating_mul(1 << exp).min(self.config.ban)
+        };
+        wait.saturating_sub(failures.last.elapsed())
+    }
+
+    /// Reserve a login attempt for the address, or how long it has to wait before it may try again
+    ///
+    /// The attempt counts as a failure until it is verified, so concurrent guesses can't outrun the ban.
+    pub fn check(&self, ip: IpAddr) -> Result<LoginAttempt, Duration> {
+        let now = Instant::now();
+        let ban = self.config.ban;
+        if self.failures.len() > 10000 {
+            self.failures
+                .retain(|_, failures| failures.pending > 0 || failures.last.elapsed() < ban);
+        }
+        let mut failures = self.failures.entry(ip).or_insert(LoginFailures {
+            count: 0,
+            pending: 0,
+            last: now,
+        });
+        if failures.count > 0 && failures.last.elapsed() >= ban {
+            // forget old failures
+            failures.count = 0;
+        }
+        let wait = self.blocked_for(&failures);
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        if failures.count + failures.pending >= self.config.max_failures {
+            // the remaining attempts are being verified right now
+            return Err(self.config.backoff.max(Duration::from_secs(1)));
+        }
+        failures.pending += 1;
+        Ok(LoginAttempt {
+            guard: self.clone(),
+            ip,
+            finished: false,
+        })
+    }
+
+    fn finish(&self, ip: IpAddr, failed: Option<bool>) {
+        let Some(mut failures) = self.failures.get_mut(&ip) else {
+            return;
+        };
+        failures.pending = failures.pending.saturating_sub(1);
+        match failed {
+            Some(true) => {
+                failures.count += 1;
+                failures.last = Instant::now();
+                if failures.count == self.config.max_failures {
+                    warn!(
+                        ip = %ip,
+                        failures = failures.count,
+                        ban_secs = self.config.ban.as_secs(),
+                        "too many failed logins, address banned"
+                    );
+                }
+            }
+            Some(false) => failures.count = 0,
+            None => {}
+        }
+        let unused = failures.count == 0 && failures.pending == 0;
+        drop(failures);
+        if unused {
+            self.failures.remove_if(&ip, |_, failures| failures.count == 0 && failures.pending == 0);
+        }
+    }
+}
+
+/// A login attempt reserved by `LoginGuard::check`, released without counting as a failure when
+/// dropped unfinished, for example when the client sent no credentials or went away
+#[derive(Debug)]
+pub struct LoginAttempt {
+    guard: LoginGuard,
+    ip: IpAddr,
+    finished: bool,
+}
+
+impl LoginAttempt {
+    pub fn succeeded(mut self) {
+        self.finished = true;
+        self.guard.finish(self.ip, Some(false));
+    }
+
+    pub fn failed(mut self) {
+        self.finished = true;
+        self.guard.finish(self.ip, Some(true));
+    }
+}
+
+impl Drop for LoginAttempt {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.guard.finish(self.ip, None);
+        }
+    }
+}
+
+/// The forwarded client addresses from the nearest to the farthest hop,
+/// from `Forwarded` if present or `X-Forwarded-For`, `None` for obfuscated or invalid entries
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node.trim_matches('"')))
+            })
+            .collect();
+    }
+    values("x-forwarded-for").into_iter().map(parse_node).collect()
+}
+
+/// Parse `1.2.3.4`, `1.2.3.4:80`, `::1` or `[::1]:80`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<std::net::SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_password() {
+        let plain = Password::parse("secret");
+        assert!(plain.verify("secret"));
+        assert!(!plain.verify("secre"));
+
+        for algorithm in [HashAlgorithm::Argon2, HashAlgorithm::Bcrypt] {
+            let hash = hash_password("secret", algorithm).unwrap();
+            let password = Password::parse(&hash);
+            assert!(password.verify("secret"));
+            assert!(!password.verify("Secret"));
+        }
+    }
+
+    #[test]
+    fn test_dummy_password() {
+        let dummy = Password::parse("secret").dummy();
+        assert!(matches!(dummy, Password::Plain(ref password) if !password.is_empty()));
+        assert!(!dummy.verify(""));
+
+        let hash = bcrypt::hash("secret", 5).unwrap();
+        let Password::Bcrypt(dummy) = Password::parse(&hash).dummy() else {
+            panic!("dummy of a bcrypt hash must be bcrypt");
+        };
+        assert!(dummy.starts_with("$2b$05$"));
+        assert!(!bcrypt::verify("secret", &dummy).unwrap());
+
+        let hash = hash_password("secret", HashAlgorithm::Argon2).unwrap();
+        let Password::Argon2(dummy) = Password::parse(&hash).dummy() else {
+            panic!("dummy of an argon2 hash must be argon2");
+        };
+        assert_ne!(dummy, hash);
+        assert_eq!(dummy.rsplitn(3, '$').nth(2), hash.rsplitn(3, '$').nth(2));
+        assert!(!Password::Argon2(dummy).verify("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_verified_cache() {
+        let cache = VerifiedCache::new(Duration::from_secs(60));
+        let password = Password::parse(&bcrypt::hash("secret", 4).unwrap());
+        assert!(!cache.verify(&password, "admin", "wrong").await);
+        assert!(cache.verify(&password, "admin", "secret").await);
+        assert!(cache.verified.contains_key(&cache.cache_key("admin", "secret")));
+        assert!(!cache.verified.contains_key(&cache.cache_key("admin", "wrong")));
+        assert!(cache.verify(&password, "admin", "secret").await);
+        // the key is random, so other processes don't share cache keys
+        assert_ne!(
+            VerifiedCache::new(Duration::from_secs(60)).cache_key("admin", "secret"),
+            cache.cache_key("admin", "secret")
+        );
+    }
+
+    #[test]
+    fn test_login_guard() {
+        let guard = LoginGuard::new(LoginGuardConfig {
+            max_failures: 3,
+            backoff: Duration::from_secs(10),
+            ban: Duration::from_secs(3600),
+            trusted_proxies: Vec::new(),
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        // an attempt without credentials is not a failure
+        drop(guard.check(ip).unwrap());
+        assert!(guard.failures.is_empty());
+
+        guard.check(ip).unwrap().failed();
+        assert!(guard.check(ip).unwrap_err() <= Duration::from_secs(10));
+        guard.failures.get_mut(&ip).unwrap().last -= Duration::from_secs(10);
+        guard.check(ip).unwrap().failed();
+        let wait = guard.check(ip).unwrap_err();
+        assert!(wait > Duration::from_secs(10) && wait <= Duration::from_secs(20));
+        guard.failures.get_mut(&ip).unwrap().last -= Duration::from_secs(20);
+        guard.check(ip).unwrap().failed();
+        assert!(guard.check(ip).unwrap_err() > Duration::from_secs(3500));
+
+        guard.failures.get_mut(&ip).unwrap().count = 1;
+        guard.failures.get_mut(&ip).unwrap().last -= Duration::from_secs(10);
+        guard.check(ip).unwrap().succeeded();
+        assert!(guard.failures.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_attempts() {
+        let guard = LoginGuard::new(LoginGuardConfig {
+            max_failures: 3,
+            backoff: Duration::ZERO,
+            ban: Duration::from_secs(3600),
+            trusted_proxies: Vec::new(),
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        // attempts still being verified use up the allowed failures
+        let attempts: Vec<_> = (0..3).map(|_| guard.check(ip).unwrap()).collect();
+        assert!(guard.check(ip).is_err());
+        for attempt in attempts {
+            attempt.failed();
+        }
+        assert!(guard.check(ip).unwrap_err() > Duration::from_secs(3500));
+        assert!(guard.check("127.0.0.2".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let guard = LoginGuard::new(LoginGuardConfig {
+            max_failures: 3,
+            backoff: Duration::from_secs(10),
+            ban: Duration::from_secs(3600),
+            trusted_proxies: vec![proxy, "10.0.0.2".parse().unwrap()],
+        });
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, value.parse().unwrap());
+            }
+            headers
+        };
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+        // direct clients can't pretend to be someone else
+        let forged = headers(&[("x-forwarded-for", "198.51.100.1")]);
+        assert_eq!(guard.client_ip(client, &forged), Some(client));
+
+        let forwarded = headers(&[("x-forwarded-for", "198.51.100.1, 203.0.113.7, 10.0.0.2")]);
+        assert_eq!(guard.client_ip(proxy, &forwarded), Some(client));
+        let forwarded = headers(&[("forwarded", "for=198.51.100.1"), ("forwarded", "for=\"[2001:db8::1]:4711\";proto=https")]);
+        assert_eq!(guard.client_ip(proxy, &forwarded), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(guard.client_ip(proxy, &headers(&[("forwarded", "for=_hidden")])), None);
+
+        // the proxy itself is never banned
+        assert_eq!(guard.client_ip(proxy, &HeaderMap::new()), None);
+    }
+}
@@ -1,9 +1,11 @@
 use std::path::Path;
 use std::time::Duration;
+use anyhow::Result;
 use moka::future::Cache as MokaCache;
 use tracing::debug;
-use crate::drive::{DriveConfig, QuarkDrive};
+use crate::drive::QuarkDrive;
 use crate::drive::model::QuarkFile;
+use crate::stats;
 
 #[derive(Clone)]
 pub struct Cache {
@@ -22,54 +24,66 @@ impl Cache {
         
         Self { inner , drive}
     }
-    pub async fn get_or_insert(&self, key: &str) -> Option<Vec<QuarkFile>> {
+    /// Cached files of a directory, listing it and any uncached parents on a miss.
+    /// `None` when the directory doesn't exist.
+    pub async fn get_or_insert(&self, key: &str) -> Result<Option<Vec<QuarkFile>>> {
         debug!(key = %key, "cache: get_or_insert");
-        if let Some(files) = self.get(key).await {
-            return Some(files);
+        let files = self.get(key).await;
+        stats::record_cache_lookup(files.is_some(), self.inner.entry_count());
+        if let Some(files) = files {
+            return Ok(Some(files));
         }
         if key == "/" {
-            self.dfs(QuarkFile::new_root(), key, "/").await;
+            self.dfs(QuarkFile::new_root(), key, "/").await?;
         }else {
             let mut path = Path::new(key);
             let mut cached_files:Vec<QuarkFile> = Vec::new();
             while let Some(parent) = path.parent() {
-                if let Some(c_files) = self.get(parent.to_str().unwrap()).await {
+                if let Some(c_files) = self.get(&parent.to_string_lossy()).await {
                     cached_files = c_files;
                     break;
                 }
                 path = parent;
-                if (path.to_str() == Some("/")) {
+                if path.to_str() == Some("/") {
                     break;
                 }
             }
-            if (path.to_str() == Some("/")) {
-                self.dfs(QuarkFile::new_root(), key, "/").await;
+            if path.to_str() == Some("/") {
+                self.dfs(QuarkFile::new_root(), key, "/").await?;
             }else {
-                let dsf_root_file = cached_files.iter().filter(|quark_file| {
-                    quark_file.file_name == path.to_str().unwrap().split("/").last().unwrap()
-                }).last().cloned().unwrap();
-                self.dfs(dsf_root_file, key, path.to_str().unwrap()).await;
+                let dir_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+                let Some(dsf_root_file) = cached_files.iter().rfind(|quark_file| {
+                    quark_file.file_name == dir_name
+                }).cloned() else {
+                    debug!(key = %key, dir = %path.display(), "cache: parent directory not found");
+                    return Ok(None);
+                };
+                self.dfs(dsf_root_file, key, &path.to_string_lossy()).await?;
             }
 
         }
         if let Some(files) = self.get(key).await {
-            Some(files)
+            Ok(Some(files))
         }else {
             debug!(key = %key, "cache: no files found for key");
-            None
+            Ok(None)
         }
     }
 
-    async fn dfs(&self, file: QuarkFile, target_path: &str, dfs_path: &str) {
+    async fn dfs(&self, file: QuarkFile, target_path: &str, dfs_path: &str) -> Result<()> {
         if file.dir {
             let mut current_files = Vec::<QuarkFile>::new();
             for page_no in 1..=20 {
-                let (files, total) = self.drive.get_files_by_pdir_fid(&file.fid, page_no, ONE_PAGE).await.unwrap();
-                let files = files.unwrap();
+                let (files, total) = self.drive.get_files_by_pdir_fid(&file.fid, page_no, ONE_PAGE).await?;
+                let Some(files) = files else {
+                    // the folder was deleted
+                    debug!(path = %dfs_path, "cache: folder not found");
+                    return Ok(());
+                };
                 let size = files.list.len();
                 current_files.extend(files.list);
                 // guess: es limit is 10000
-                if size < ONE_PAGE as usize || page_no >= total / ONE_PAGE + 1   {
+                if size < ONE_PAGE as usize || page_no > total / ONE_PAGE {
                     break;
                 }
             }
@@ -77,7 +91,7 @@ impl Cache {
             self.insert(dfs_path.to_string(), current_files.clone()).await;
             debug!("{} in cache", &dfs_path);
             if dfs_path == target_path {
-                return;
+                return Ok(());
             }
             for curr_f in current_files {
                 let file_path = if dfs_path == "/" {
@@ -86,11 +100,12 @@ impl Cache {
                     format!("{}/{}", dfs_path, curr_f.file_name)
                 };
                 if target_path.starts_with(&file_path) {
-                    Box::pin(self.dfs(curr_f, target_path, &file_path)).await;
+                    Box::pin(self.dfs(curr_f, target_path, &file_path)).await?;
                 }
             }
 
         }
+        Ok(())
     }
 
     async fn get(&self, key: &str) -> Option<Vec<QuarkFile>> {
@@ -103,12 +118,37 @@ impl Cache {
         self.inner.insert(key, value).await;
     }
 
+    /// Add or replace a file in the cached entries of its parent directory
+    pub async fn insert_file(&self, dir: &Path, file: QuarkFile) {
+        let key = dir.to_string_lossy().into_owned();
+        debug!(key = %key, file_name = %file.file_name, "cache: insert file");
+        if let Some(mut files) = self.get(&key).await {
+            files.retain(|f| f.file_name != file.file_name);
+            files.push(file);
+            self.insert(key, files).await;
+        }
+    }
+
     pub async fn invalidate(&self, path: &Path) {
         let key = path.to_string_lossy().into_owned();
         debug!(path = %path.display(), key = %key, "cache: invalidate");
         self.inner.invalidate(&key).await;
     }
 
+    /// Invalidate a directory and all cached directories below it
+    pub async fn invalidate_subtree(&self, path: &Path) {
+        debug!(path = %path.display(), "cache: invalidate subtree");
+        let keys: Vec<String> = self
+            .inner
+            .iter()
+            .filter(|(key, _)| Path::new(key.as_str()).starts_with(path))
+            .map(|(key, _)| key.to_string())
+            .collect();
+        for key in keys {
+            self.inner.invalidate(&key).await;
+        }
+    }
+
     pub async fn invalidate_parent(&self, path: &Path) {
         if let Some(parent) = path.parent() {
             self.invalidate(parent).await;
@@ -119,4 +159,27 @@ impl Cache {
         debug!("cache: invalidate all");
         self.inner.invalidate_all();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::mock::MockQuark;
+
+    #[test]
+    fn test_one_lookup_per_call() {
+        let metrics = stats::capture(|| async {
+            let mock = MockQuark::start().await;
+            let a = mock.add_dir("0", "a");
+            let b = mock.add_dir(&a, "b");
+            mock.add_dir(&b, "c");
+            let cache = Cache::new(100, 600, mock.drive());
+            // the uncached parents are probed on the way up
+            assert!(cache.get_or_insert("/a/b/c").await.unwrap().unwrap().is_empty());
+            assert_eq!(cache.get_or_insert("/a/b").await.unwrap().unwrap().len(), 1);
+            assert!(cache.get_or_insert("/a/missing").await.unwrap().is_none());
+        });
+        assert!(metrics.contains("cache_misses_total 2"));
+        assert!(metrics.contains("cache_hits_total 1"));
+    }
+}
@@ -0,0 +1,254 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{AuthScheme, UserConfig};
+use crate::drive::AccountConfig;
+use crate::Opt;
+
+const REDACTED: &str = "********";
+
+/// `[tls]` section of the configuration file
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+}
+
+macro_rules! file_config {
+    (
+        values { $($field:ident: $ty:ty,)* }
+        options { $($opt_field:ident: $opt_ty:ty,)* }
+    ) => {
+        /// The TOML configuration file, keys are the long option names with `_` instead of `-`
+        #[derive(Debug, Default, Deserialize, Serialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct FileConfig {
+            $(
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                pub $field: Option<$ty>,
+            )*
+            $(
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                pub $opt_field: Option<$opt_ty>,
+            )*
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub tls: Option<TlsSection>,
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            pub users: Vec<UserConfig>,
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            pub accounts: Vec<AccountConfig>,
+        }
+
+        impl FileConfig {
+            /// Fill in the options not given on the command line or in the environment
+            pub fn apply(self, opt: &mut Opt, matches: &ArgMatches) {
+                $(
+                    if let Some(value) = self.$field
+                        && !is_explicit(matches, stringify!($field))
+                    {
+                        opt.$field = value;
+                    }
+                )*
+                $(
+                    if let Some(value) = self.$opt_field
+                        && !is_explicit(matches, stringify!($opt_field))
+                    {
+                        opt.$opt_field = Some(value);
+                    }
+                )*
+                if let Some(tls) = self.tls {
+                    if let Some(cert) = tls.cert
+                        && !is_explicit(matches, "tls_cert")
+                    {
+                        opt.tls_cert = Some(cert);
+                    }
+                    if let Some(key) = tls.key
+                        && !is_explicit(matches, "tls_key")
+                    {
+                        opt.tls_key = Some(key);
+                    }
+                }
+                opt.users = self.users;
+                opt.accounts = self.accounts;
+            }
+
+            /// The effective configuration with passwords and cookies redacted, `users` and `accounts`
+            /// are the merged ones from the configuration file, the users file and the accounts file
+            pub fn redacted(opt: &Opt, users: &[UserConfig], accounts: &[AccountConfig]) -> Self {
+                let mut config = Self {
+                    $($field: Some(opt.$field.clone()),)*
+                    $($opt_field: opt.$opt_field.clone(),)*
+                    tls: (opt.tls_cert.is_some() || opt.tls_key.is_some()).then(|| TlsSection {
+                        cert: opt.tls_cert.clone(),
+                        key: opt.tls_key.clone(),
+                    }),
+                    users: users.to_vec(),
+                    accounts: accounts.to_vec(),
+                };
+                redact(&mut config.quark_cookie);
+                redact(&mut config.auth_password);
+                redact(&mut config.admin_token);
+                for user in &mut config.users {
+                    user.password = REDACTED.to_string();
+                }
+                for account in &mut config.accounts {
+                    redact(&mut account.cookie);
+                }
+                config
+            }
+        }
+    };
+}
+
+file_config! {
+    values {
+        host: String,
+        port: u16,
+        listen: Vec<String>,
+        auth_scheme: AuthScheme,
+        auth_max_failures: u32,
+        auth_backoff_secs: u64,
+        auth_ban_secs: u64,
+        trusted_proxies: Vec<IpAddr>,
+        auto_index: bool,
+        read_buffer_size: usize,
+        upload_buffer_size: usize,
+        cache_size: u64,
+        cache_ttl: u64,
+        root: String,
+        no_trash: bool,
+        read_only: bool,
+        debug: bool,
+        no_self_upgrade: bool,
+        auto_upgrade: bool,
+        upgrade_url: String,
+        skip_upload_same_size: bool,
+        prefer_http_download: bool,
+        redirect: bool,
+        refresh_cache_secs_interval: u64,
+        shutdown_timeout: u64,
+    }
+    options {
+        quark_cookie: String,
+        state_dir: PathBuf,
+        auth_user: String,
+        auth_password: String,
+        accounts_file: PathBuf,
+        users_file: PathBuf,
+        strip_prefix: String,
+        metrics_listen: SocketAddr,
+        health_listen: SocketAddr,
+        admin_listen: SocketAddr,
+        admin_token: String,
+    }
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+/// Whether an option was given on the command line or in the environment
+fn is_explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+fn redact(secret: &mut Option<String>) {
+    if secret.is_some() {
+        *secret = Some(REDACTED.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    #[test]
+    fn test_file_config_precedence() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            port = 9090
+            cache_ttl = 60
+            read_only = true
+            quark_cookie = "secret"
+
+            [tls]
+            cert = "/etc/cert.pem"
+            key = "/etc/key.pem"
+
+            [[users]]
+            username = "alice"
+            password = "hunter2"
+            "#,
+        )
+        .unwrap();
+        let matches = Opt::command()
+            .try_get_matches_from(["quarkdrive-webdav", "-p", "8081"])
+            .unwrap();
+        let mut opt = Opt::from_arg_matches(&matches).unwrap();
+        file.apply(&mut opt, &matches);
+        assert_eq!(opt.port, 8081);
+        assert_eq!(opt.cache_ttl, 60);
+        assert!(opt.read_only);
+        assert_eq!(opt.tls_key, Some(PathBuf::from("/etc/key.pem")));
+        assert_eq!(opt.users[0].username, "alice");
+
+        let redacted = FileConfig::redacted(&opt, &opt.users, &opt.accounts).to_toml().unwrap();
+        assert!(!redacted.contains("secret"));
+        assert!(!redacted.contains("hunter2"));
+    }
+
+    #[test]
+    fn test_options_with_subcommand() {
+        let dir = tempfile::tempdir().unwrap();
+        let users_file = dir.path().join("users.json");
+        std::fs::write(&users_file, r#"[{"username": "bob", "password": "hunter2"}]"#).unwrap();
+        let accounts_file = dir.path().join("accounts.json");
+        std::fs::write(&accounts_file, r#"[{"name": "work", "cookie": "secret"}]"#).unwrap();
+        let users_file = users_file.to_str().unwrap();
+        let accounts_file = accounts_file.to_str().unwrap();
+
+        for args in [
+            ["-p", "1", "--users-file", users_file, "--accounts-file", accounts_file, "config", "check"],
+            ["config", "check", "-p", "1", "--users-file", users_file, "--accounts-file", accounts_file],
+        ] {
+            let matches = Opt::command()
+                .try_get_matches_from(std::iter::once("quarkdrive-webdav").chain(args))
+                .unwrap();
+            let opt = Opt::from_arg_matches(&matches).unwrap();
+            assert_eq!(opt.port, 1);
+            assert!(is_explicit(&matches, "port"));
+
+            let (users, accounts) = crate::check_config(&opt).unwrap();
+            let redacted = FileConfig::redacted(&opt, &users, &accounts).to_toml().unwrap();
+            assert!(redacted.contains("bob") && redacted.contains("work"), "{}", redacted);
+            assert!(!redacted.contains("secret"));
+            assert!(!redacted.contains("hunter2"));
+        }
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        assert!(toml::from_str::<FileConfig>("no_such_option = true").is_err());
+    }
+}
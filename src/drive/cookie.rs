@@ -0,0 +1,344 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use reqwest::cookie::CookieStore;
+use reqwest::header::HeaderValue;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, info};
+use url::Url;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CookieState {
+    /// Hash of the configured cookie this one was refreshed from, `None` for a QR code login
+    #[serde(default)]
+    source: Option<String>,
+    cookie: String,
+}
+
+type Cookies = Arc<RwLock<Vec<(String, String)>>>;
+
+/// The Quark session cookie, kept up to date from `Set-Cookie` responses and persisted to a state file
+///
+/// It is only sent to and updated from Quark hosts and the configured API host.
+#[derive(Debug)]
+pub struct CookieJar {
+    cookies: Cookies,
+    api_host: Option<String>,
+    writer: Option<Arc<StateWriter>>,
+}
+
+/// Writes the state file on the blocking thread pool, with at most one write pending
+#[derive(Debug)]
+struct StateWriter {
+    path: PathBuf,
+    source: Option<String>,
+    cookies: Cookies,
+    pending: AtomicBool,
+    lock: Mutex<()>,
+}
+
+impl StateWriter {
+    fn write(&self) {
+        let _guard = self.lock.lock().unwrap();
+        // changes from now on schedule another write
+        self.pending.store(false, Ordering::SeqCst);
+        let state = CookieState {
+            source: self.source.clone(),
+            cookie: join_cookie(&self.cookies.read().unwrap()),
+        };
+        if let Err(err) = write_state(&self.path, &state) {
+            error!(path = %self.path.display(), error = %err, "save cookie failed");
+        }
+    }
+}
+
+impl CookieJar {
+    /// The refreshed cookie in the state file wins over the configured cookie it was refreshed from,
+    /// a different configured cookie means the user logged in again and replaces the state.
+    pub fn load(configured: Option<&str>, state_file: Option<PathBuf>, api_base_url: &str) -> Result<Self> {
+        let source = configured.map(hash_cookie);
+        let state = state_file.as_deref().and_then(read_state);
+        let cookie = match (configured, state) {
+            (Some(cookie), Some(state)) if state.source != source => cookie.to_string(),
+            (_, Some(state)) => {
+                info!("using refreshed cookie from the state file");
+                state.cookie
+            }
+            (Some(cookie), None) => cookie.to_string(),
+            (None, None) => anyhow::bail!("Please set QUARK_COOKIE or log in with `qr login`!"),
+        };
+        let cookies = Arc::new(RwLock::new(parse_cookie(&cookie)));
+        let writer = state_file.map(|path| {
+            Arc::new(StateWriter {
+                path,
+                source,
+                cookies: cookies.clone(),
+                pending: AtomicBool::new(false),
+                lock: Mutex::new(()),
+            })
+        });
+        Ok(Self {
+            cookies,
+            api_host: Url::parse(api_base_url)
+                .ok()
+                .and_then(|url| url.host_str().map(String::from)),
+            writer,
+        })
+    }
+
+    pub fn cookie(&self) -> String {
+        join_cookie(&self.cookies.read().unwrap())
+    }
+
+    fn is_quark(&self, url: &Url) -> bool {
+        url.host_str().is_some_and(|host| {
+            host == "quark.cn" || host.ends_with(".quark.cn") || self.api_host.as_deref() == Some(host)
+        })
+    }
+
+    /// Replace the whole cookie, it keeps the source so the state file still wins over the configured cookie
+    pub fn replace(&self, cookie: &str) -> Result<()> {
+        let cookies = parse_cookie(cookie);
+        if cookies.is_empty() {
+            anyhow::bail!("invalid cookie, expected `name=value; ...`");
+        }
+        *self.cookies.write().unwrap() = cookies;
+        self.save();
+        Ok(())
+    }
+
+    /// Apply one `Set-Cookie` header, returns whether the cookie changed
+    fn merge(&self, set_cookie: &str) -> bool {
+        let Some((name, value)) = parse_set_cookie(set_cookie) else {
+            return false;
+        };
+        let mut cookies = self.cookies.write().unwrap();
+        let pos = cookies.iter().position(|(n, _)| *n == name);
+        match (pos, value) {
+            (Some(pos), Some(value)) if cookies[pos].1 != value => cookies[pos].1 = value,
+            (None, Some(value)) => cookies.push((name.clone(), value)),
+            (Some(pos), None) => {
+                cookies.remove(pos);
+            }
+            _ => return false,
+        }
+        debug!(name = %name, "cookie updated");
+        true
+    }
+
+    /// Persist the cookie without blocking the caller, which may be a response on the runtime
+    fn save(&self) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        // the pending write picks up this change too
+        if writer.pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let writer = writer.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || writer.write());
+            }
+            Err(_) => writer.write(),
+        }
+    }
+}
+
+impl CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        // ignore cookies from the object storage and other third parties
+        if !self.is_quark(url) {
+            return;
+        }
+        let mut changed = false;
+        for header in cookie_headers {
+            if let Ok(set_cookie) = header.to_str() {
+                changed |= self.merge(set_cookie);
+            }
+        }
+        if changed {
+            self.save();
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        // never hand the session to the object storage or download hosts of others
+        if !self.is_quark(url) {
+            return None;
+        }
+        HeaderValue::from_str(&self.cookie()).ok()
+    }
+}
+
+/// Save the cookie of a QR code login, used on the next start when QUARK_COOKIE is not set
+pub fn save_login_cookie(state_file: &Path, cookie: &str) -> Result<()> {
+    write_state(
+        state_file,
+        &CookieState {
+            source: None,
+            cookie: cookie.to_string(),
+        },
+    )
+}
+
+fn join_cookie(cookies: &[(String, String)]) -> String {
+    cookies
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn parse_cookie(cookie: &str) -> Vec<(String, String)> {
+    cookie
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Returns the cookie name and value, `None` value when the cookie is deleted
+fn parse_set_cookie(set_cookie: &str) -> Option<(String, Option<String>)> {
+    let mut parts = set_cookie.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let (name, value) = (name.trim(), value.trim());
+    if name.is_empty() {
+        return None;
+    }
+    let expired = parts.any(|attr| {
+        let (key, val) = attr.split_once('=').unwrap_or((attr, ""));
+        match key.trim().to_ascii_lowercase().as_str() {
+            "max-age" => val.trim().parse::<i64>().is_ok_and(|age| age <= 0),
+            "expires" => httpdate::parse_http_date(val.trim()).is_ok_and(|t| t <= SystemTime::now()),
+            _ => false,
+        }
+    });
+    let value = (!expired && !value.is_empty()).then(|| value.to_string());
+    Some((name.to_string(), value))
+}
+
+fn hash_cookie(cookie: &str) -> String {
+    format!("{:x}", Sha256::digest(cookie.trim().as_bytes()))
+}
+
+fn read_state(path: &Path) -> Option<CookieState> {
+    let content = fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&content) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            error!(path = %path.display(), error = %err, "invalid state file");
+            None
+        }
+    }
+}
+
+fn write_state(path: &Path, state: &CookieState) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    // a leftover from an interrupted write keeps its mode, start over so the cookie is never readable by others
+    match fs::remove_file(&tmp) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(&serde_json::to_vec_pretty(state)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::drive::mock::MockQuark;
+    use crate::drive::QuarkDrive;
+
+    #[test]
+    fn test_merge_set_cookie() {
+        let jar = CookieJar::load(Some("__pus=a; __puus=b; ctoken=c"), None, "https://drive.quark.cn").unwrap();
+        assert!(jar.merge("__puus=d; Domain=.quark.cn; Path=/; Max-Age=86400; HttpOnly"));
+        assert!(!jar.merge("__puus=d; Path=/"));
+        assert!(jar.merge("__kp=e; Path=/"));
+        assert!(jar.merge("ctoken=; Max-Age=0"));
+        assert!(jar.merge("__pus=deleted; Expires=Thu, 01 Jan 1970 00:00:00 GMT"));
+        assert_eq!(jar.cookie(), "__puus=d; __kp=e");
+    }
+
+    #[test]
+    fn test_cookies_only_for_quark() {
+        let jar = CookieJar::load(Some("__pus=a"), None, "http://127.0.0.1:8080").unwrap();
+        let cookies = |url: &str| jar.cookies(&Url::parse(url).unwrap());
+        assert!(cookies("https://drive.quark.cn/1/clouddrive/file").is_some());
+        assert!(cookies("https://quark.cn/").is_some());
+        assert!(cookies("http://127.0.0.1:8080/1/clouddrive/file").is_some());
+        assert!(cookies("https://mock-bucket.pds.aliyuncs.com/obj").is_none());
+        assert!(cookies("https://notquark.cn/").is_none());
+        assert!(cookies("https://quark.cn.example.com/").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_state_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        // left over by an interrupted write
+        let tmp = state_file.with_extension("tmp");
+        fs::write(&tmp, "").unwrap();
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644)).unwrap();
+
+        save_login_cookie(&state_file, "__pus=secret").unwrap();
+        let mode = fs::metadata(&state_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!tmp.exists());
+        assert_eq!(read_state(&state_file).unwrap().cookie, "__pus=secret");
+    }
+
+    #[tokio::test]
+    async fn test_refreshed_cookie_is_persisted() {
+        let mock = MockQuark::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        let mut config = mock.config();
+        config.state_file = Some(state_file.clone());
+        let drive = QuarkDrive::new(config).unwrap();
+
+        mock.refresh_cookie("__puus=refreshed; Path=/; HttpOnly");
+        drive.check_session().await.unwrap();
+        // the state file is written in the background
+        let mut state = None;
+        for _ in 0..50 {
+            state = read_state(&state_file);
+            if state.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(state.unwrap().cookie, "__pus=mock; __puus=refreshed");
+        drive.check_session().await.unwrap();
+        assert_eq!(mock.cookies().last().unwrap(), "__pus=mock; __puus=refreshed");
+
+        // the next start picks up the refreshed cookie
+        let jar = CookieJar::load(Some("__pus=mock"), Some(state_file), &mock.url).unwrap();
+        assert_eq!(jar.cookie(), "__pus=mock; __puus=refreshed");
+    }
+}
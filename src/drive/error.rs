@@ -0,0 +1,117 @@
+use std::fmt;
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+/// A failed Quark API call, classified so callers can answer with a fitting status
+#[derive(Debug)]
+pub enum QuarkError {
+    /// The API could not be reached or the connection broke
+    Network(String),
+    /// The cookie expired or has no access to the file
+    Auth(String),
+    NotFound(String),
+    /// A file with the same name is already in the folder
+    Exists(String),
+    RateLimited(String),
+    /// Any other failure reported by the API
+    Api { status: StatusCode, message: String },
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    #[serde(default)]
+    code: u32,
+    #[serde(default)]
+    message: String,
+}
+
+/// The code of a name conflict, its message reads `file is doloading[同名冲突]`
+const CODE_NAME_CONFLICT: u32 = 23008;
+
+impl QuarkError {
+    /// Classify an unsuccessful response, the body usually carries a JSON `code` and `message`
+    pub fn from_response(status: StatusCode, body: &str) -> Self {
+        let res = serde_json::from_str::<ErrorResponse>(body).ok();
+        let code = res.as_ref().map(|res| res.code).unwrap_or_default();
+        let message = res
+            .map(|res| res.message)
+            .filter(|message| !message.is_empty())
+            .unwrap_or_else(|| body.chars().take(200).collect());
+        if code == CODE_NAME_CONFLICT || message.contains("同名冲突") {
+            return QuarkError::Exists(message);
+        }
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => QuarkError::Auth(message),
+            StatusCode::NOT_FOUND => QuarkError::NotFound(message),
+            StatusCode::TOO_MANY_REQUESTS => QuarkError::RateLimited(message),
+            status => QuarkError::Api { status, message },
+        }
+    }
+}
+
+impl fmt::Display for QuarkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuarkError::Network(message) => write!(f, "network error: {}", message),
+            QuarkError::Auth(message) => write!(f, "not authorized: {}", message),
+            QuarkError::NotFound(message) => write!(f, "not found: {}", message),
+            QuarkError::Exists(message) => write!(f, "already exists: {}", message),
+            QuarkError::RateLimited(message) => write!(f, "rate limited: {}", message),
+            QuarkError::Api { status, message } => write!(f, "api error {}: {}", status, message),
+        }
+    }
+}
+
+impl std::error::Error for QuarkError {}
+
+impl From<reqwest::Error> for QuarkError {
+    fn from(err: reqwest::Error) -> Self {
+        match err.status() {
+            Some(status) => QuarkError::from_response(status, &err.to_string()),
+            None if err.is_decode() => QuarkError::Api {
+                status: StatusCode::OK,
+                message: err.to_string(),
+            },
+            None => QuarkError::Network(err.to_string()),
+        }
+    }
+}
+
+impl From<reqwest_middleware::Error> for QuarkError {
+    fn from(err: reqwest_middleware::Error) -> Self {
+        match err {
+            reqwest_middleware::Error::Reqwest(err) => err.into(),
+            reqwest_middleware::Error::Middleware(err) => QuarkError::Network(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_response() {
+        let err = QuarkError::from_response(
+            StatusCode::UNAUTHORIZED,
+            r#"{"status":401,"code":31001,"message":"require login [guest]"}"#,
+        );
+        assert!(matches!(err, QuarkError::Auth(ref message) if message == "require login [guest]"));
+        assert!(matches!(
+            QuarkError::from_response(StatusCode::TOO_MANY_REQUESTS, "slow down"),
+            QuarkError::RateLimited(_)
+        ));
+        assert!(matches!(
+            QuarkError::from_response(
+                StatusCode::BAD_REQUEST,
+                r#"{"status":400,"code":23008,"message":"file is doloading[同名冲突]"}"#,
+            ),
+            QuarkError::Exists(_)
+        ));
+        assert!(matches!(
+            QuarkError::from_response(StatusCode::BAD_GATEWAY, ""),
+            QuarkError::Api { status: StatusCode::BAD_GATEWAY, .. }
+        ));
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderValue, SET_COOKIE};
+use reqwest::redirect::Policy;
+use tokio::time;
+use tracing::debug;
+use uuid::Uuid;
+
+use super::model::QrLoginResponse;
+use super::{ORIGIN, REFERER, UA};
+
+const UOP_BASE_URL: &str = "https://uop.quark.cn";
+const QRCODE_BASE_URL: &str = "https://su.quark.cn/4_eMHBJ";
+const ACCOUNT_INFO_URL: &str = "https://pan.quark.cn/account/info";
+const CLIENT_ID: &str = "532";
+
+const STATUS_OK: u32 = 2000000;
+const STATUS_NOT_SCANNED: u32 = 50004001;
+const STATUS_EXPIRED: u32 = 50004002;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QrCodeStatus {
+    /// Not scanned or not confirmed on the phone yet
+    Waiting,
+    Expired,
+    Confirmed { service_ticket: String },
+}
+
+/// Quark QR code login, scanned with the Quark app to get a cookie for `QUARK_COOKIE`
+#[derive(Clone)]
+pub struct QrLogin {
+    client: reqwest::Client,
+    uop_base_url: String,
+    account_info_url: String,
+    poll_interval: Duration,
+}
+
+impl QrLogin {
+    pub fn new() -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert("Origin", HeaderValue::from_static(ORIGIN));
+        headers.insert("Referer", HeaderValue::from_static(REFERER));
+        let client = reqwest::Client::builder()
+            .user_agent(UA)
+            .default_headers(headers)
+            // the cookies are set on the account info response itself
+            .redirect(Policy::none())
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        Ok(Self {
+            client,
+            uop_base_url: UOP_BASE_URL.to_string(),
+            account_info_url: ACCOUNT_INFO_URL.to_string(),
+            poll_interval: POLL_INTERVAL,
+        })
+    }
+
+    /// Request a new QR code token
+    pub async fn generate(&self) -> Result<String> {
+        let url = format!(
+            "{}/cas/ajax/getTokenForQrcodeLogin?client_id={}&v=1.2&request_id={}",
+            self.uop_base_url,
+            CLIENT_ID,
+            Uuid::new_v4()
+        );
+        let res: QrLoginResponse = self.client.get(url).send().await?.error_for_status()?.json().await?;
+        debug!(status = res.status, message = %res.message, "qrcode token");
+        if res.status != STATUS_OK {
+            anyhow::bail!("get qrcode token failed: {} {}", res.status, res.message);
+        }
+        res.data
+            .and_then(|data| data.members.token)
+            .context("no token in qrcode response")
+    }
+
+    /// The URL encoded in the QR code for `token`
+    pub fn qrcode_url(token: &str) -> String {
+        format!(
+            "{}?token={}&client_id={}&ssb=weblogin&uc_param_str=&uc_biz_str=S%3Acustom%7COPT%3ASAREA%400%7COPT%3AIMMERSIVE%401%7COPT%3ABACK_BTN_STYLE%400",
+            QRCODE_BASE_URL, token, CLIENT_ID
+        )
+    }
+
+    pub async fn query(&self, token: &str) -> Result<QrCodeStatus> {
+        let url = format!(
+            "{}/cas/ajax/getServiceTicketByQrcodeToken?client_id={}&v=1.2&token={}&request_id={}",
+            self.uop_base_url,
+            CLIENT_ID,
+            token,
+            Uuid::new_v4()
+        );
+        let res: QrLoginResponse = self.client.get(url).send().await?.error_for_status()?.json().await?;
+        debug!(status = res.status, message = %res.message, "qrcode status");
+        match res.status {
+            STATUS_OK => {
+                let service_ticket = res
+                    .data
+                    .and_then(|data| data.members.service_ticket)
+                    .context("no service ticket in qrcode response")?;
+                Ok(QrCodeStatus::Confirmed { service_ticket })
+            }
+            STATUS_NOT_SCANNED => Ok(QrCodeStatus::Waiting),
+            STATUS_EXPIRED => Ok(QrCodeStatus::Expired),
+            status => anyhow::bail!("query qrcode status failed: {} {}", status, res.message),
+        }
+    }
+
+    /// Exchange a service ticket for the drive cookie
+    pub async fn get_cookie(&self, service_ticket: &str) -> Result<String> {
+        let res = self
+            .client
+            .get(&self.account_info_url)
+            .query(&[("st", service_ticket), ("lw", "scan")])
+            .send()
+            .await?;
+        let cookie = res
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| value.split(';').next())
+            .map(str::trim)
+            .filter(|pair| pair.contains('='))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if cookie.is_empty() {
+            anyhow::bail!("no cookie returned, status {}", res.status());
+        }
+        Ok(cookie)
+    }
+
+    /// Poll until the QR code is confirmed, then return the cookie
+    pub async fn wait_for_cookie(&self, token: &str) -> Result<String> {
+        loop {
+            match self.query(token).await? {
+                QrCodeStatus::Waiting => time::sleep(self.poll_interval).await,
+                QrCodeStatus::Expired => anyhow::bail!("qrcode expired, please generate a new one"),
+                QrCodeStatus::Confirmed { service_ticket } => return self.get_cookie(&service_ticket).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::mock::MockQuark;
+
+    fn login(mock: &MockQuark) -> QrLogin {
+        let mut login = QrLogin::new().unwrap();
+        login.uop_base_url = mock.url.clone();
+        login.account_info_url = format!("{}/account/info", mock.url);
+        login.poll_interval = Duration::from_millis(10);
+        login
+    }
+
+    #[tokio::test]
+    async fn test_qr_login() {
+        let mock = MockQuark::start().await;
+        // confirmed on the phone after the third poll
+        mock.set_qr_polls(2);
+        let login = login(&mock);
+        let token = login.generate().await.unwrap();
+        assert!(QrLogin::qrcode_url(&token).contains(&format!("token={}&client_id={}", token, CLIENT_ID)));
+        assert_eq!(login.query(&token).await.unwrap(), QrCodeStatus::Waiting);
+        assert_eq!(login.wait_for_cookie(&token).await.unwrap(), "__pus=mock-pus; __puus=mock-puus");
+        assert!(login.get_cookie("forged").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_qr_expired() {
+        let mock = MockQuark::start().await;
+        let login = login(&mock);
+        assert_eq!(login.query("unknown").await.unwrap(), QrCodeStatus::Expired);
+        let err = login.wait_for_cookie("unknown").await.unwrap_err();
+        assert!(err.to_string().contains("expired"));
+    }
+}
@@ -0,0 +1,691 @@
+//! An in-process fake of the Quark drive API and its object storage, for tests
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use md5::{Digest, Md5};
+use serde_json::{json, Value};
+use sha1::Sha1;
+use tokio::net::TcpListener;
+
+use super::{DriveConfig, QuarkDrive};
+
+const BUCKET: &str = "mock-bucket";
+const TIMESTAMP: u64 = 1_700_000_000_000;
+
+#[derive(Debug, Clone)]
+pub struct MockFile {
+    pub fid: String,
+    pub pdir_fid: String,
+    pub name: String,
+    pub dir: bool,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MockUpload {
+    pub pdir_fid: String,
+    pub name: String,
+    pub size: u64,
+    pub fid: String,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+    pub parts: BTreeMap<u64, Vec<u8>>,
+    pub completed: Option<Vec<u8>>,
+    pub aborted: bool,
+    pub finished: bool,
+    pub instant: bool,
+}
+
+#[derive(Default)]
+struct MockState {
+    files: BTreeMap<String, MockFile>,
+    recycle: Vec<(String, MockFile)>,
+    uploads: BTreeMap<String, MockUpload>,
+    tasks: HashMap<String, Vec<String>>,
+    next_id: u64,
+    requests: Vec<String>,
+    cookies: Vec<String>,
+    failures: HashSet<String>,
+    /// Polls of a new QR code before it is confirmed
+    qr_polls: u32,
+    /// Remaining polls of the QR codes handed out
+    qr_tokens: HashMap<String, u32>,
+    /// `Set-Cookie` sent with the API responses
+    set_cookie: Option<String>,
+}
+
+impl MockState {
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{}", prefix, self.next_id)
+    }
+
+    fn children(&self, pdir_fid: &str) -> impl Iterator<Item = &MockFile> {
+        self.files.values().filter(move |f| f.pdir_fid == pdir_fid)
+    }
+
+    fn name_taken(&self, pdir_fid: &str, name: &str) -> bool {
+        self.children(pdir_fid).any(|f| f.name == name)
+    }
+
+    /// Quark appends `(n)` to the name when the folder already has one
+    fn free_name(&self, pdir_fid: &str, name: &str) -> String {
+        if !self.name_taken(pdir_fid, name) {
+            return name.to_string();
+        }
+        let (stem, ext) = match name.rfind('.') {
+            Some(i) if i > 0 => (&name[..i], &name[i..]),
+            _ => (name, ""),
+        };
+        (1..)
+            .map(|n| format!("{}({}){}", stem, n, ext))
+            .find(|candidate| !self.name_taken(pdir_fid, candidate))
+            .unwrap()
+    }
+
+    fn copy_tree(&mut self, fid: &str, to_pdir_fid: &str, name: String) -> String {
+        let new_fid = self.next_id("fid");
+        let file = self.files[fid].clone();
+        self.files.insert(
+            new_fid.clone(),
+            MockFile {
+                fid: new_fid.clone(),
+                pdir_fid: to_pdir_fid.to_string(),
+                name,
+                ..file
+            },
+        );
+        let children: Vec<MockFile> = self.children(fid).cloned().collect();
+        for child in children {
+            self.copy_tree(&child.fid, &new_fid, child.name.clone());
+        }
+        new_fid
+    }
+
+    fn new_task(&mut self, fids: Vec<String>) -> Value {
+        let task_id = self.next_id("task");
+        self.tasks.insert(task_id.clone(), fids);
+        json!({ "task_id": task_id, "finish": false })
+    }
+}
+
+/// A Quark API on a random local port, also serving downloads and path-style OSS uploads
+pub struct MockQuark {
+    pub url: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockQuark {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let url = format!("http://{}", addr);
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let server_state = state.clone();
+        let base_url = url.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let state = server_state.clone();
+                let base_url = base_url.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| {
+                        let state = state.clone();
+                        let base_url = base_url.clone();
+                        async move { Ok::<_, Infallible>(handle(req, state, base_url).await) }
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        Self { url, state }
+    }
+
+    pub fn config(&self) -> DriveConfig {
+        DriveConfig {
+            api_base_url: self.url.clone(),
+            cookie: Some("__pus=mock".to_string()),
+            state_file: None,
+        }
+    }
+
+    pub fn drive(&self) -> QuarkDrive {
+        QuarkDrive::new(self.config()).unwrap()
+    }
+
+    pub fn add_dir(&self, pdir_fid: &str, name: &str) -> String {
+        self.add(pdir_fid, name, true, Vec::new())
+    }
+
+    pub fn add_file(&self, pdir_fid: &str, name: &str, content: &[u8]) -> String {
+        self.add(pdir_fid, name, false, content.to_vec())
+    }
+
+    fn add(&self, pdir_fid: &str, name: &str, dir: bool, content: Vec<u8>) -> String {
+        let mut state = self.state.lock().unwrap();
+        let fid = state.next_id("fid");
+        state.files.insert(
+            fid.clone(),
+            MockFile {
+                fid: fid.clone(),
+                pdir_fid: pdir_fid.to_string(),
+                name: name.to_string(),
+                dir,
+                content,
+            },
+        );
+        fid
+    }
+
+    /// The file at a `/`-separated path from the drive root
+    pub fn find(&self, path: &str) -> Option<MockFile> {
+        let state = self.state.lock().unwrap();
+        let mut current: Option<MockFile> = None;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let pdir_fid = current.as_ref().map(|f| f.fid.clone()).unwrap_or_else(|| "0".to_string());
+            current = Some(state.children(&pdir_fid).find(|f| f.name == name)?.clone());
+        }
+        current
+    }
+
+    pub fn content(&self, path: &str) -> Option<Vec<u8>> {
+        self.find(path).map(|f| f.content)
+    }
+
+    /// Names in a folder, sorted
+    pub fn list(&self, path: &str) -> Vec<String> {
+        let pdir_fid = if path.trim_matches('/').is_empty() {
+            "0".to_string()
+        } else {
+            self.find(path).unwrap().fid
+        };
+        let state = self.state.lock().unwrap();
+        let mut names: Vec<String> = state.children(&pdir_fid).map(|f| f.name.clone()).collect();
+        names.sort();
+        names
+    }
+
+    /// Names of the top-level items in the recycle bin
+    pub fn recycled(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.recycle.iter().map(|(_, f)| f.name.clone()).collect()
+    }
+
+    /// Put `count` unrelated items into the recycle bin
+    pub fn fill_recycle_bin(&self, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        for i in 0..count {
+            let record_id = state.next_id("record");
+            let fid = state.next_id("fid");
+            let file = MockFile {
+                fid,
+                pdir_fid: "0".to_string(),
+                name: format!("old-{}", i),
+                dir: false,
+                content: Vec::new(),
+            };
+            state.recycle.push((record_id, file));
+        }
+    }
+
+    pub fn uploads(&self) -> Vec<MockUpload> {
+        self.state.lock().unwrap().uploads.values().cloned().collect()
+    }
+
+    /// Answer requests to `path` with an API error from now on
+    pub fn fail(&self, path: &str) {
+        self.state.lock().unwrap().failures.insert(path.to_string());
+    }
+
+    /// The `Cookie` headers of the API requests so far
+    pub fn cookies(&self) -> Vec<String> {
+        self.state.lock().unwrap().cookies.clone()
+    }
+
+    /// Refresh the session with a `Set-Cookie` header on every API response from now on
+    pub fn refresh_cookie(&self, set_cookie: &str) {
+        self.state.lock().unwrap().set_cookie = Some(set_cookie.to_string());
+    }
+
+    /// Confirm new QR codes only after `polls` status queries
+    pub fn set_qr_polls(&self, polls: u32) {
+        self.state.lock().unwrap().qr_polls = polls;
+    }
+}
+
+fn qr_response(status: u32, data: Value) -> Response<Full<Bytes>> {
+    json_response(StatusCode::OK, json!({ "status": status, "message": "mock", "data": data }))
+}
+
+fn ok(data: Value) -> Response<Full<Bytes>> {
+    ok_with_metadata(data, json!({}))
+}
+
+fn ok_with_metadata(data: Value, metadata: Value) -> Response<Full<Bytes>> {
+    let body = json!({
+        "status": 200,
+        "code": 0,
+        "message": "ok",
+        "timestamp": TIMESTAMP / 1000,
+        "data": data,
+        "metadata": metadata,
+    });
+    json_response(StatusCode::OK, body)
+}
+
+fn api_error(status: StatusCode, code: u32, message: &str) -> Response<Full<Bytes>> {
+    let body = json!({ "status": status.as_u16(), "code": code, "message": message });
+    json_response(status, body)
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+fn empty(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder().status(status).body(Full::new(Bytes::new())).unwrap()
+}
+
+fn file_json(file: &MockFile) -> Value {
+    json!({
+        "fid": file.fid,
+        "file_name": file.name,
+        "pdir_fid": file.pdir_fid,
+        "size": file.content.len(),
+        "format_type": if file.dir { "" } else { "application/octet-stream" },
+        "status": 1,
+        "created_at": TIMESTAMP,
+        "updated_at": TIMESTAMP,
+        "dir": file.dir,
+        "file": !file.dir,
+    })
+}
+
+fn hashes(content: &[u8]) -> (String, String) {
+    (format!("{:x}", Md5::digest(content)), format!("{:x}", Sha1::digest(content)))
+}
+
+fn str_field<'a>(body: &'a Value, field: &str) -> &'a str {
+    body[field].as_str().unwrap_or_default()
+}
+
+fn fids_field(body: &Value, field: &str) -> Vec<String> {
+    body[field]
+        .as_array()
+        .map(|fids| fids.iter().filter_map(|fid| fid.as_str().map(String::from)).collect())
+        .unwrap_or_default()
+}
+
+async fn handle(req: Request<Incoming>, state: Arc<Mutex<MockState>>, base_url: String) -> Response<Full<Bytes>> {
+    let api = req.uri().path().starts_with("/1/");
+    let set_cookie = state.lock().unwrap().set_cookie.clone();
+    let mut res = route(req, state, base_url).await;
+    if let (true, Some(set_cookie)) = (api, set_cookie) {
+        res.headers_mut().append("Set-Cookie", set_cookie.parse().unwrap());
+    }
+    res
+}
+
+async fn route(req: Request<Incoming>, state: Arc<Mutex<MockState>>, base_url: String) -> Response<Full<Bytes>> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query: HashMap<String, String> = url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+        .into_owned()
+        .collect();
+    let range = req
+        .headers()
+        .get("Range")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let cookie = req
+        .headers()
+        .get("Cookie")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let body = req.into_body().collect().await.map(|b| b.to_bytes()).unwrap_or_default();
+    let json_body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+    let mut state = state.lock().unwrap();
+    state.requests.push(format!("{} {}", method, path));
+    if path.starts_with("/1/") {
+        state.cookies.push(cookie.unwrap_or_default());
+    }
+    if state.failures.contains(&path) {
+        return api_error(StatusCode::BAD_REQUEST, 99999, "mock failure");
+    }
+
+    match (&method, path.as_str()) {
+        (&Method::GET, "/cas/ajax/getTokenForQrcodeLogin") => {
+            let token = state.next_id("qr-token");
+            let polls = state.qr_polls;
+            state.qr_tokens.insert(token.clone(), polls);
+            qr_response(2000000, json!({ "members": { "token": token } }))
+        }
+        (&Method::GET, "/cas/ajax/getServiceTicketByQrcodeToken") => {
+            let token = query.get("token").cloned().unwrap_or_default();
+            match state.qr_tokens.get_mut(&token) {
+                None => qr_response(50004002, Value::Null),
+                Some(0) => qr_response(2000000, json!({ "members": { "service_ticket": format!("st-{}", token) } })),
+                Some(polls) => {
+                    *polls -= 1;
+                    qr_response(50004001, Value::Null)
+                }
+            }
+        }
+        (&Method::GET, "/account/info") => {
+            let ticket = query.get("st").cloned().unwrap_or_default();
+            let confirmed = ticket
+                .strip_prefix("st-")
+                .is_some_and(|token| state.qr_tokens.get(token) == Some(&0));
+            if !confirmed || query.get("lw").map(String::as_str) != Some("scan") {
+                return api_error(StatusCode::UNAUTHORIZED, 31001, "invalid service ticket");
+            }
+            Response::builder()
+                .status(StatusCode::FOUND)
+                .header("Location", "https://pan.quark.cn/")
+                .header("Set-Cookie", "__pus=mock-pus; Domain=.quark.cn; Path=/; HttpOnly")
+                .header("Set-Cookie", "__puus=mock-puus; Domain=.quark.cn; Path=/")
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        }
+        (&Method::GET, "/1/clouddrive/file/sort") => {
+            let pdir_fid = query.get("pdir_fid").cloned().unwrap_or_default();
+            if pdir_fid != "0" && !state.files.get(&pdir_fid).is_some_and(|f| f.dir) {
+                return api_error(StatusCode::NOT_FOUND, 41013, "file not found");
+            }
+            let page: usize = query.get("_page").and_then(|p| p.parse().ok()).unwrap_or(1);
+            let size: usize = query.get("_size").and_then(|s| s.parse().ok()).unwrap_or(50);
+            let files: Vec<Value> = state.children(&pdir_fid).map(file_json).collect();
+            let total = files.len();
+            let list: Vec<Value> = files.into_iter().skip((page - 1) * size).take(size).collect();
+            let count = list.len();
+            ok_with_metadata(
+                json!({ "list": list }),
+                json!({ "_total": total, "_count": count, "_page": page }),
+            )
+        }
+        (&Method::POST, "/1/clouddrive/file/download") => {
+            let list: Vec<Value> = fids_field(&json_body, "fids")
+                .into_iter()
+                .filter(|fid| state.files.contains_key(fid))
+                .map(|fid| json!({ "fid": fid, "download_url": format!("{}/download/{}", base_url, fid) }))
+                .collect();
+            ok(json!(list))
+        }
+        (&Method::GET, path) if path.starts_with("/download/") => {
+            let fid = path.trim_start_matches("/download/");
+            let Some(file) = state.files.get(fid) else {
+                return empty(StatusCode::NOT_FOUND);
+            };
+            let content = &file.content;
+            let (status, slice) = match range.as_deref().and_then(|r| r.strip_prefix("bytes=")) {
+                Some(range) => {
+                    let (start, end) = range.split_once('-').unwrap();
+                    let start: usize = start.parse().unwrap();
+                    let end: usize = end.parse::<usize>().unwrap().min(content.len().saturating_sub(1));
+                    if start >= content.len() {
+                        (StatusCode::RANGE_NOT_SATISFIABLE, Vec::new())
+                    } else {
+                        (StatusCode::PARTIAL_CONTENT, content[start..=end].to_vec())
+                    }
+                }
+                None => (StatusCode::OK, content.clone()),
+            };
+            Response::builder()
+                .status(status)
+                .body(Full::new(Bytes::from(slice)))
+                .unwrap()
+        }
+        (&Method::POST, "/1/clouddrive/file") => {
+            let pdir_fid = str_field(&json_body, "pdir_fid").to_string();
+            let name = str_field(&json_body, "file_name").to_string();
+            if state.name_taken(&pdir_fid, &name) {
+                return api_error(StatusCode::BAD_REQUEST, 23008, "file is doloading[同名冲突]");
+            }
+            let fid = state.next_id("fid");
+            state.files.insert(
+                fid.clone(),
+                MockFile {
+                    fid: fid.clone(),
+                    pdir_fid,
+                    name,
+                    dir: true,
+                    content: Vec::new(),
+                },
+            );
+            ok(json!({ "fid": fid }))
+        }
+        (&Method::POST, "/1/clouddrive/file/delete") => {
+            for fid in fids_field(&json_body, "filelist") {
+                let Some(file) = state.files.remove(&fid) else {
+                    return api_error(StatusCode::NOT_FOUND, 41013, "file not found");
+                };
+                let record_id = state.next_id("record");
+                state.recycle.push((record_id, file));
+            }
+            let task = state.new_task(Vec::new());
+            ok(task)
+        }
+        (&Method::GET, "/1/clouddrive/task") => {
+            let task_id = query.get("task_id").cloned().unwrap_or_default();
+            let Some(fids) = state.tasks.get(&task_id) else {
+                return api_error(StatusCode::NOT_FOUND, 32003, "task not found");
+            };
+            ok(json!({
+                "task_id": task_id,
+                "status": 2,
+                "save_as": { "save_as_top_fids": fids },
+            }))
+        }
+        (&Method::GET, "/1/clouddrive/file/recycle/list") => {
+            let page: usize = query.get("_page").and_then(|p| p.parse().ok()).unwrap_or(1);
+            let size: usize = query.get("_size").and_then(|s| s.parse().ok()).unwrap_or(50);
+            let list: Vec<Value> = state
+                .recycle
+                .iter()
+                .skip((page - 1) * size)
+                .take(size)
+                .map(|(record_id, file)| json!({ "record_id": record_id, "fid": file.fid, "file_name": file.name }))
+                .collect();
+            let total = state.recycle.len();
+            let count = list.len();
+            ok_with_metadata(
+                json!({ "list": list }),
+                json!({ "_total": total, "_count": count, "_page": page }),
+            )
+        }
+        (&Method::POST, "/1/clouddrive/file/recycle/remove") => {
+            let records = fids_field(&json_body, "record_list");
+            state.recycle.retain(|(record_id, _)| !records.contains(record_id));
+            ok(json!({}))
+        }
+        (&Method::POST, "/1/clouddrive/file/rename") => {
+            let fid = str_field(&json_body, "fid").to_string();
+            let name = str_field(&json_body, "file_name").to_string();
+            let Some(pdir_fid) = state.files.get(&fid).map(|f| f.pdir_fid.clone()) else {
+                return api_error(StatusCode::NOT_FOUND, 41013, "file not found");
+            };
+            if state.name_taken(&pdir_fid, &name) {
+                return api_error(StatusCode::BAD_REQUEST, 23008, "file is doloading[同名冲突]");
+            }
+            state.files.get_mut(&fid).unwrap().name = name;
+            ok(json!({}))
+        }
+        (&Method::POST, "/1/clouddrive/file/move") => {
+            let to_pdir_fid = str_field(&json_body, "to_pdir_fid").to_string();
+            let fids = fids_field(&json_body, "filelist");
+            for fid in &fids {
+                let Some(file) = state.files.get(fid) else {
+                    return api_error(StatusCode::NOT_FOUND, 41013, "file not found");
+                };
+                if state.name_taken(&to_pdir_fid, &file.name) {
+                    return api_error(StatusCode::BAD_REQUEST, 23008, "file is doloading[同名冲突]");
+                }
+            }
+            for fid in &fids {
+                state.files.get_mut(fid).unwrap().pdir_fid = to_pdir_fid.clone();
+            }
+            let task = state.new_task(Vec::new());
+            ok(task)
+        }
+        (&Method::POST, "/1/clouddrive/file/copy") => {
+            let to_pdir_fid = str_field(&json_body, "to_pdir_fid").to_string();
+            let mut copies = Vec::new();
+            for fid in fids_field(&json_body, "filelist") {
+                let Some(file) = state.files.get(&fid) else {
+                    return api_error(StatusCode::NOT_FOUND, 41013, "file not found");
+                };
+                let name = state.free_name(&to_pdir_fid, &file.name.clone());
+                copies.push(state.copy_tree(&fid, &to_pdir_fid, name));
+            }
+            let task = state.new_task(copies);
+            ok(task)
+        }
+        (&Method::GET, "/1/clouddrive/member") => ok(json!({
+            "total_capacity": 1000,
+            "use_capacity": 100,
+        })),
+        (&Method::POST, "/1/clouddrive/file/upload/pre") => {
+            let task_id = state.next_id("task");
+            let fid = state.next_id("fid");
+            let upload = MockUpload {
+                pdir_fid: str_field(&json_body, "pdir_fid").to_string(),
+                name: str_field(&json_body, "file_name").to_string(),
+                size: json_body["size"].as_u64().unwrap_or_default(),
+                fid: fid.clone(),
+                ..Default::default()
+            };
+            state.uploads.insert(task_id.clone(), upload);
+            ok(json!({
+                "task_id": task_id,
+                "finish": false,
+                "upload_id": format!("upload-{}", task_id),
+                "obj_key": format!("obj/{}", task_id),
+                "upload_url": base_url,
+                "fid": fid,
+                "bucket": BUCKET,
+                "callback": { "callbackUrl": "callback", "callbackBody": "body" },
+                "format_type": "application/octet-stream",
+                "auth_info": "auth-info",
+            }))
+        }
+        (&Method::POST, "/1/clouddrive/file/update/hash") => {
+            let task_id = str_field(&json_body, "task_id").to_string();
+            let md5 = str_field(&json_body, "md5").to_string();
+            let sha1 = str_field(&json_body, "sha1").to_string();
+            let existing = state
+                .files
+                .values()
+                .find(|f| !f.dir && hashes(&f.content) == (md5.clone(), sha1.clone()))
+                .map(|f| f.content.clone());
+            let Some(upload) = state.uploads.get_mut(&task_id) else {
+                return api_error(StatusCode::NOT_FOUND, 32003, "task not found");
+            };
+            upload.md5 = Some(md5);
+            upload.sha1 = Some(sha1);
+            let Some(content) = existing else {
+                return ok(json!({ "finish": false }));
+            };
+            upload.instant = true;
+            upload.finished = true;
+            let upload = upload.clone();
+            let name = state.free_name(&upload.pdir_fid, &upload.name);
+            state.files.insert(
+                upload.fid.clone(),
+                MockFile {
+                    fid: upload.fid.clone(),
+                    pdir_fid: upload.pdir_fid,
+                    name,
+                    dir: false,
+                    content,
+                },
+            );
+            ok(json!({ "finish": true }))
+        }
+        (&Method::POST, "/1/clouddrive/file/upload/auth") => ok(json!({ "auth_key": "mock-auth" })),
+        (&Method::POST, "/1/clouddrive/file/upload/finish") => {
+            let task_id = str_field(&json_body, "task_id").to_string();
+            let Some(upload) = state.uploads.get(&task_id).cloned() else {
+                return api_error(StatusCode::NOT_FOUND, 32003, "task not found");
+            };
+            let Some(content) = upload.completed.clone() else {
+                return api_error(StatusCode::BAD_REQUEST, 32004, "upload not completed");
+            };
+            let (md5, sha1) = hashes(&content);
+            if upload.md5.as_ref() != Some(&md5) || upload.sha1.as_ref() != Some(&sha1) {
+                return api_error(StatusCode::BAD_REQUEST, 32005, "hash mismatch");
+            }
+            let name = state.free_name(&upload.pdir_fid, &upload.name);
+            state.files.insert(
+                upload.fid.clone(),
+                MockFile {
+                    fid: upload.fid.clone(),
+                    pdir_fid: upload.pdir_fid.clone(),
+                    name,
+                    dir: false,
+                    content,
+                },
+            );
+            state.uploads.get_mut(&task_id).unwrap().finished = true;
+            ok(json!({}))
+        }
+        (_, path) if path.starts_with(&format!("/{}/obj/", BUCKET)) => {
+            let task_id = path.rsplit('/').next().unwrap_or_default().to_string();
+            let Some(upload) = state.uploads.get_mut(&task_id) else {
+                return empty(StatusCode::NOT_FOUND);
+            };
+            if upload.aborted || query.get("uploadId") != Some(&format!("upload-{}", task_id)) {
+                return empty(StatusCode::NOT_FOUND);
+            }
+            match method {
+                Method::PUT => {
+                    let part: u64 = query.get("partNumber").and_then(|p| p.parse().ok()).unwrap_or_default();
+                    upload.parts.insert(part, body.to_vec());
+                    Response::builder()
+                        .header("ETag", format!("\"etag-{}\"", part))
+                        .body(Full::new(Bytes::new()))
+                        .unwrap()
+                }
+                Method::POST => {
+                    let xml = String::from_utf8_lossy(&body);
+                    let mut content = Vec::new();
+                    for (i, part) in xml.split("<PartNumber>").skip(1).enumerate() {
+                        let number: u64 = part.split('<').next().unwrap().parse().unwrap();
+                        let expected = format!("<ETag>\"etag-{}\"</ETag>", number);
+                        if number != i as u64 + 1 || !part.contains(&expected) {
+                            return empty(StatusCode::BAD_REQUEST);
+                        }
+                        let Some(data) = upload.parts.get(&number) else {
+                            return empty(StatusCode::BAD_REQUEST);
+                        };
+                        content.extend_from_slice(data);
+                    }
+                    upload.completed = Some(content);
+                    empty(StatusCode::OK)
+                }
+                Method::DELETE => {
+                    upload.aborted = true;
+                    empty(StatusCode::NO_CONTENT)
+                }
+                _ => empty(StatusCode::METHOD_NOT_ALLOWED),
+            }
+        }
+        _ => api_error(StatusCode::NOT_FOUND, 404, "no such api"),
+    }
+}
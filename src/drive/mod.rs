@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use model::*;
 
@@ -7,9 +9,9 @@ use reqwest_retry::{Jitter, RetryTransientMiddleware};
 use reqwest_retry::policies::ExponentialBackoff;
 use anyhow::{Context, Result};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::time;
-use tracing::{debug};
+use tracing::{debug, info, warn};
 
 use reqwest::{
     header::{HeaderMap, HeaderValue},
@@ -21,13 +23,23 @@ use dav_server::fs::{DavDirEntry, DavMetaData, FsFuture, FsResult};
 
 use bytes::Bytes;
 use moka::future::FutureExt;
+use crate::stats;
+use cookie::CookieJar;
+use error::QuarkError;
 
+pub mod cookie;
+pub mod error;
+pub mod login;
+#[cfg(test)]
+pub mod mock;
 pub mod model;
 
 pub use model::{QuarkFile};
 
 const ORIGIN: &str = "https://pan.quark.cn";
 const REFERER: &str = "https://pan.quark.cn/";
+const RECYCLE_PAGE_SIZE: usize = 100;
+const OSS_USER_AGENT: &str = "aliyun-sdk-js/6.6.1 Chrome 98.0.4758.80 on Windows 10 64-bit";
 const UA: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) quark-cloud-drive/2.5.20 Chrome/100.0.4896.160 Electron/18.3.5.4-b478491100 Safari/537.36 Channel/pckk_other_ch";
 
 
@@ -35,12 +47,62 @@ const UA: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (
 pub struct DriveConfig {
     pub api_base_url: String,
     pub cookie: Option<String>,
+    /// Where the refreshed cookie is persisted
+    pub state_file: Option<PathBuf>,
+}
+
+/// A named Quark account, served as a top-level folder
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    pub name: String,
+    /// Falls back to the cookie saved in the account's state file
+    #[serde(default)]
+    pub cookie: Option<String>,
+    /// Root directory in the account, defaults to the global root
+    #[serde(default)]
+    pub root: Option<String>,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub no_trash: bool,
+}
+
+/// Load accounts from a JSON file containing an array of accounts
+pub fn load_accounts(path: &Path) -> Result<Vec<AccountConfig>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read accounts file {}", path.display()))?;
+    let accounts: Vec<AccountConfig> = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse accounts file {}", path.display()))?;
+    validate_accounts(&accounts)?;
+    Ok(accounts)
+}
+
+/// Account names must be unique and safe to use in URLs and file names
+pub fn validate_accounts(accounts: &[AccountConfig]) -> Result<()> {
+    for (i, account) in accounts.iter().enumerate() {
+        // the name is used as is in URLs and state file names
+        if account.name.is_empty()
+            || !account
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            || account.name.starts_with('.')
+        {
+            anyhow::bail!("invalid account name {:?}, use letters, digits, '-', '_' and '.'", account.name);
+        }
+        if accounts[..i].iter().any(|a| a.name == account.name) {
+            anyhow::bail!("account {} is defined more than once", account.name);
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
 pub struct QuarkDrive {
     config: DriveConfig,
     client: ClientWithMiddleware,
+    cookie_jar: Arc<CookieJar>,
 }
 
 impl DavMetaData for QuarkFile {
@@ -66,7 +128,7 @@ impl DavDirEntry for QuarkFile {
         self.file_name.as_bytes().to_vec()
     }
 
-    fn metadata(&self) -> FsFuture<Box<dyn DavMetaData>> {
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
         async move { Ok(Box::new(self.clone()) as Box<dyn DavMetaData>) }.boxed()
     }
 }
@@ -78,8 +140,11 @@ impl QuarkDrive {
         let mut headers = HeaderMap::new();
         headers.insert("Origin", HeaderValue::from_static(ORIGIN));
         headers.insert("Referer", HeaderValue::from_static(REFERER));
-        let cookie = config.cookie.as_ref().expect("Please set QUARK_COOKIE in config!");
-        headers.insert("Cookie", HeaderValue::from_str(cookie)?);
+        let cookie_jar = Arc::new(CookieJar::load(
+            config.cookie.as_deref(),
+            config.state_file.clone(),
+            &config.api_base_url,
+        )?);
         let retry_policy = ExponentialBackoff::builder()
             .retry_bounds(Duration::from_secs(3), Duration::from_secs(7))
             .jitter(Jitter::Bounded)
@@ -89,6 +154,7 @@ impl QuarkDrive {
         let client = reqwest::Client::builder()
             .user_agent(UA)
             .default_headers(headers)
+            .cookie_provider(cookie_jar.clone())
             // OSS closes idle connections after 60 seconds,
             // so we can close idle connections ahead of time to prevent re-using them.
             // See also https://github.com/hyperium/hyper/issues/2136
@@ -102,6 +168,7 @@ impl QuarkDrive {
         let drive = Self {
             config,
             client,
+            cookie_jar,
         };
 
 
@@ -113,54 +180,36 @@ impl QuarkDrive {
         U: DeserializeOwned,
     {
         let url = reqwest::Url::parse(&url)?;
-        let res = self
+        stats::record_api_request(url.path());
+        let res = self.send_get_request(&url).await;
+        if res.is_err() {
+            stats::record_api_error(url.path());
+        }
+        res
+    }
+
+    async fn send_get_request<U>(&self, url: &reqwest::Url) -> Result<Option<U>>
+    where
+        U: DeserializeOwned,
+    {
+        let mut res = self
             .client
             .get(url.clone())
             .send()
-            .await?;
-        match res.error_for_status_ref() {
-            Ok(_) => {
-                if res.status() == StatusCode::NO_CONTENT {
-                    return Ok(None);
-                }
-                // let res = res.text().await?;
-                // println!("{}: {}", url, res);
-                // let res = serde_json::from_str(&res)?;
-                let res = res.json::<U>().await?;
-                Ok(Some(res))
-            }
-            Err(err) => {
-                let err_msg = res.text().await?;
-                debug!(error = %err_msg, url = %url, "request failed");
-                match err.status() {
-                    Some(
-                        _status_code
-                        @
-                        // 4xx
-                        ( StatusCode::REQUEST_TIMEOUT
-                        | StatusCode::TOO_MANY_REQUESTS
-                        // 5xx
-                        | StatusCode::INTERNAL_SERVER_ERROR
-                        | StatusCode::BAD_GATEWAY
-                        | StatusCode::SERVICE_UNAVAILABLE
-                        | StatusCode::GATEWAY_TIMEOUT),
-                    ) => {
-                        time::sleep(Duration::from_secs(1)).await;
-                        let res = self
-                            .client
-                            .get(url.clone())
-                            .send()
-                            .await?;
-                        if res.status() == StatusCode::NO_CONTENT {
-                            return Ok(None);
-                        }
-                        let res = res.json::<U>().await?;
-                        Ok(Some(res))
-                    }
-                    _ => Err(err.into()),
-                }
-            }
+            .await
+            .map_err(QuarkError::from)?;
+        if is_retryable(res.status()) {
+            debug!(status = %res.status(), url = %url, "request failed, retrying");
+            stats::record_api_retry(url.path());
+            time::sleep(Duration::from_secs(1)).await;
+            res = self
+                .client
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(QuarkError::from)?;
         }
+        parse_response(url, res).await
     }
 
 
@@ -170,58 +219,41 @@ impl QuarkDrive {
         U: DeserializeOwned,
     {
         let url = reqwest::Url::parse(&url)?;
-        let res= self
+        stats::record_api_request(url.path());
+        let res = self.send_post_request(&url, r).await;
+        if res.is_err() {
+            stats::record_api_error(url.path());
+        }
+        res
+    }
+
+    async fn send_post_request<T, U>(&self, url: &reqwest::Url, r: &T) -> Result<Option<U>>
+    where
+        T: Serialize + ?Sized,
+        U: DeserializeOwned,
+    {
+        let mut res = self
             .client
             .post(url.clone())
             .json(&r)
             .header("Content-Type", "application/json")
             .send()
-            .await?;
-
-        match res.error_for_status_ref() {
-            Ok(_) => {
-                if res.status() == StatusCode::NO_CONTENT {
-                    return Ok(None);
-                }
-                // let res = res.text().await?;
-                // println!("{}: {}", url, res);
-                // let res = serde_json::from_str(&res)?;
-                let res = res.json::<U>().await?;
-                Ok(Some(res))
-            }
-            Err(err) => {
-                let err_msg = res.text().await?;
-                debug!(error = %err_msg, url = %url, "request failed");
-                match err.status() {
-                    Some(
-                        _status_code
-                        @
-                        // 4xx
-                        ( StatusCode::REQUEST_TIMEOUT
-                        | StatusCode::TOO_MANY_REQUESTS
-                        // 5xx
-                        | StatusCode::INTERNAL_SERVER_ERROR
-                        | StatusCode::BAD_GATEWAY
-                        | StatusCode::SERVICE_UNAVAILABLE
-                        | StatusCode::GATEWAY_TIMEOUT),
-                    ) => {
-                        time::sleep(Duration::from_secs(1)).await;
-                        let res = self
-                            .client
-                            .post(url)
-                            .send()
-                            .await?
-                            .error_for_status()?;
-                        if res.status() == StatusCode::NO_CONTENT {
-                            return Ok(None);
-                        }
-                        let res = res.json::<U>().await?;
-                        Ok(Some(res))
-                    }
-                    _ => Err(err.into()),
-                }
-            }
+            .await
+            .map_err(QuarkError::from)?;
+        if is_retryable(res.status()) {
+            debug!(status = %res.status(), url = %url, "request failed, retrying");
+            stats::record_api_retry(url.path());
+            time::sleep(Duration::from_secs(1)).await;
+            res = self
+                .client
+                .post(url.clone())
+                .json(&r)
+                .header("Content-Type", "application/json")
+                .send()
+                .await
+                .map_err(QuarkError::from)?;
         }
+        parse_response(url, res).await
     }
 
 
@@ -243,17 +275,8 @@ impl QuarkDrive {
                 let total = files_res.metadata.total;
                 Ok((Some(files_res.into()), total))
             },
-            Err(err) => {
-                if let Some(req_err) = err.downcast_ref::<reqwest::Error>() {
-                    if matches!(req_err.status(), Some(StatusCode::NOT_FOUND)) {
-                        Ok((None, 0u32))
-                    } else {
-                        Err(err)
-                    }
-                } else {
-                    Err(err)
-                }
-            }
+            Err(err) if matches!(err.downcast_ref::<QuarkError>(), Some(QuarkError::NotFound(_))) => Ok((None, 0u32)),
+            Err(err) => Err(err),
         }
     }
 
@@ -293,15 +316,468 @@ impl QuarkDrive {
                 .get(url)
                 .header(RANGE, range)
                 .send()
-                .await?
-                .error_for_status()?
+                .await
+                .map_err(QuarkError::from)?
         } else {
             debug!(url = %url, "download file");
-            self.client.get(url).send().await?.error_for_status()?
+            self.client.get(url).send().await.map_err(QuarkError::from)?
+        };
+        let res = res.error_for_status().map_err(QuarkError::from)?;
+        Ok(res.bytes().await.map_err(QuarkError::from)?)
+    }
+
+    pub async fn create_folder(&self, pdir_fid: &str, name: &str) -> Result<String> {
+        debug!(pdir_fid = %pdir_fid, name = %name, "create folder");
+        let req = CreateFolderRequest {
+            dir_init_lock: false,
+            dir_path: "".to_string(),
+            file_name: name.to_string(),
+            pdir_fid: pdir_fid.to_string(),
+        };
+        let res: CreateFolderResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        Ok(res.data.fid)
+    }
+
+    /// Wait for an asynchronous drive task such as delete to finish
+    async fn wait_task(&self, task: TaskData) -> Result<()> {
+        if task.finish {
+            return Ok(());
+        }
+        self.poll_task(&task.task_id).await.map(|_| ())
+    }
+
+    /// Poll a task until it is finished
+    async fn poll_task(&self, task_id: &str) -> Result<TaskStatusData> {
+        for retry_index in 0..20 {
+            let res: TaskStatusResponse = self
+                .get_request(format!(
+                    "{}/1/clouddrive/task?pr=ucpro&fr=pc&task_id={}&retry_index={}",
+                    self.config.api_base_url, task_id, retry_index
+                ))
+                .await?
+                .context("expect response")?;
+            // 2: finished
+            if res.data.status == 2 {
+                return Ok(res.data);
+            }
+            time::sleep(Duration::from_millis(500)).await;
+        }
+        anyhow::bail!("task {} not finished in time", task_id)
+    }
+
+    /// Move files to the recycle bin
+    pub async fn trash_files(&self, fids: Vec<String>) -> Result<()> {
+        debug!(fids = ?fids, "trash files");
+        let req = DeleteFilesRequest {
+            action_type: 2,
+            filelist: fids,
+            exclude_fids: Vec::new(),
         };
-        Ok(res.bytes().await?)
+        let res: TaskResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/delete?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        self.wait_task(res.data).await
     }
 
+    /// Remove files from the recycle bin permanently
+    pub async fn purge_recycled_files(&self, fids: &[String]) -> Result<()> {
+        debug!(fids = ?fids, "purge recycled files");
+        let mut record_list = Vec::new();
+        for page_no in 1.. {
+            let res: RecycleListResponse = self
+                .get_request(format!(
+                    "{}/1/clouddrive/file/recycle/list?pr=ucpro&fr=pc&_page={}&_size={}",
+                    self.config.api_base_url, page_no, RECYCLE_PAGE_SIZE
+                ))
+                .await?
+                .context("expect response")?;
+            let size = res.data.list.len();
+            record_list.extend(
+                res.data
+                    .list
+                    .into_iter()
+                    .filter(|item| fids.contains(&item.fid))
+                    .map(|item| item.record_id),
+            );
+            if record_list.len() == fids.len() || size < RECYCLE_PAGE_SIZE {
+                break;
+            }
+        }
+        if record_list.is_empty() {
+            anyhow::bail!("files not found in recycle bin: {:?}", fids);
+        }
+        let req = RecycleRemoveRequest {
+            select_mode: 2,
+            record_list,
+        };
+        let _res: RecycleRemoveResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/recycle/remove?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        Ok(())
+    }
+
+    /// Move a file to the recycle bin, and purge it from there unless `trash` is set.
+    /// The file is gone once trashed, so a failed purge is only logged.
+    pub async fn remove_file(&self, fid: &str, trash: bool) -> Result<()> {
+        let fids = vec![fid.to_string()];
+        self.trash_files(fids.clone()).await?;
+        if !trash && let Err(err) = self.purge_recycled_files(&fids).await {
+            warn!(fid = %fid, error = %err, "purge from recycle bin failed");
+        }
+        Ok(())
+    }
+
+    pub async fn rename_file(&self, fid: &str, new_name: &str) -> Result<()> {
+        debug!(fid = %fid, new_name = %new_name, "rename file");
+        let req = RenameFileRequest {
+            fid: fid.to_string(),
+            file_name: new_name.to_string(),
+        };
+        let _res: RenameFileResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/rename?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        Ok(())
+    }
+
+    pub async fn move_files(&self, fids: Vec<String>, to_pdir_fid: &str) -> Result<()> {
+        debug!(fids = ?fids, to_pdir_fid = %to_pdir_fid, "move files");
+        let req = MoveFilesRequest {
+            action_type: 1,
+            exclude_fids: Vec::new(),
+            filelist: fids,
+            to_pdir_fid: to_pdir_fid.to_string(),
+        };
+        let res: TaskResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/move?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        self.wait_task(res.data).await
+    }
+
+    /// Copy files into another folder on the server side, returns the ids of the copies
+    pub async fn copy_files(&self, fids: Vec<String>, to_pdir_fid: &str) -> Result<Vec<String>> {
+        debug!(fids = ?fids, to_pdir_fid = %to_pdir_fid, "copy files");
+        let req = CopyFilesRequest {
+            action_type: 1,
+            exclude_fids: Vec::new(),
+            filelist: fids,
+            to_pdir_fid: to_pdir_fid.to_string(),
+        };
+        let res: TaskResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/copy?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        let task = self.poll_task(&res.data.task_id).await?;
+        let fids = task.save_as.map(|save_as| save_as.save_as_top_fids).unwrap_or_default();
+        if fids.is_empty() {
+            anyhow::bail!("copy task {} returned no files", res.data.task_id);
+        }
+        Ok(fids)
+    }
+
+    /// Returns (used, total) capacity in bytes
+    async fn get_member(&self) -> Result<MemberResponse> {
+        self.get_request(format!(
+            "{}/1/clouddrive/member?pr=ucpro&fr=pc&fetch_subscribe=true&fetch_identity=true",
+            self.config.api_base_url
+        ))
+        .await?
+        .context("expect response")
+    }
+
+    pub async fn get_quota(&self) -> Result<(u64, u64)> {
+        debug!("get quota");
+        let res = self.get_member().await?;
+        Ok((res.data.use_capacity, res.data.total_capacity))
+    }
+
+    /// Replace the session cookie without a restart, persisted like a refreshed cookie
+    pub fn set_cookie(&self, cookie: &str) -> Result<()> {
+        info!("rotate cookie");
+        self.cookie_jar.replace(cookie)
+    }
+
+    /// Check that the cookie is still logged in, using the cheap account info endpoint
+    pub async fn check_session(&self) -> Result<()> {
+        debug!("check session");
+        let res = self.get_member().await?;
+        if res.code != 0 {
+            anyhow::bail!("quark session is invalid: {}", res.message);
+        }
+        Ok(())
+    }
+
+    pub async fn pre_upload(&self, pdir_fid: &str, file_name: &str, size: u64, format_type: &str) -> Result<UploadPreData> {
+        debug!(pdir_fid = %pdir_fid, file_name = %file_name, size = size, "pre upload");
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64;
+        let req = UploadPreRequest {
+            ccp_hash_update: true,
+            dir_name: "".to_string(),
+            file_name: file_name.to_string(),
+            format_type: format_type.to_string(),
+            l_created_at: now,
+            l_updated_at: now,
+            pdir_fid: pdir_fid.to_string(),
+            size,
+        };
+        let res: UploadPreResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/upload/pre?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        Ok(res.data)
+    }
+
+    pub async fn update_hash(&self, task_id: &str, md5: &str, sha1: &str) -> Result<bool> {
+        debug!(task_id = %task_id, md5 = %md5, sha1 = %sha1, "update hash");
+        let req = UploadHashRequest {
+            md5: md5.to_string(),
+            sha1: sha1.to_string(),
+            task_id: task_id.to_string(),
+        };
+        let res: UploadHashResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/update/hash?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        Ok(res.data.finish)
+    }
+
+    async fn upload_auth(&self, pre: &UploadPreData, auth_meta: String) -> Result<String> {
+        let req = UploadAuthRequest {
+            auth_info: pre.auth_info.clone(),
+            auth_meta,
+            task_id: pre.task_id.clone(),
+        };
+        let res: UploadAuthResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/upload/auth?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        Ok(res.data.auth_key)
+    }
+
+    fn oss_object_url(pre: &UploadPreData) -> String {
+        let (scheme, host) = pre
+            .upload_url
+            .split_once("://")
+            .unwrap_or(("https", pre.upload_url.as_str()));
+        let host = host.trim_end_matches('/');
+        let hostname = host
+            .rsplit_once(':')
+            .map(|(hostname, _)| hostname)
+            .unwrap_or(host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        if hostname.parse::<std::net::IpAddr>().is_ok() {
+            // an IP endpoint can't be addressed by bucket subdomain
+            format!("{}://{}/{}/{}", scheme, host, pre.bucket, pre.obj_key)
+        } else {
+            format!("https://{}.{}/{}", pre.bucket, host, pre.obj_key)
+        }
+    }
+
+    /// Upload one part of a multipart upload, returns the ETag of the part
+    pub async fn upload_part(&self, pre: &UploadPreData, mime_type: &str, part_number: u64, data: Bytes) -> Result<String> {
+        debug!(task_id = %pre.task_id, part_number = part_number, size = data.len(), "upload part");
+        let oss_date = httpdate::fmt_http_date(SystemTime::now());
+        let auth_meta = format!(
+            "PUT\n\n{}\n{}\nx-oss-date:{}\nx-oss-user-agent:{}\n/{}/{}?partNumber={}&uploadId={}",
+            mime_type, oss_date, oss_date, OSS_USER_AGENT, pre.bucket, pre.obj_key, part_number, pre.upload_id
+        );
+        let auth_key = self.upload_auth(pre, auth_meta).await?;
+        let url = format!(
+            "{}?partNumber={}&uploadId={}",
+            Self::oss_object_url(pre),
+            part_number,
+            pre.upload_id
+        );
+        let res = self
+            .client
+            .put(url)
+            .header("Authorization", auth_key)
+            .header("Content-Type", mime_type)
+            .header("x-oss-date", oss_date)
+            .header("x-oss-user-agent", OSS_USER_AGENT)
+            // parts may be much larger than regular API payloads
+            .timeout(Duration::from_secs(300))
+            .body(data)
+            .send()
+            .await?
+            .error_for_status()?;
+        let etag = res
+            .headers()
+            .get("ETag")
+            .and_then(|v| v.to_str().ok())
+            .context("missing ETag in upload part response")?;
+        Ok(etag.to_string())
+    }
+
+    pub async fn commit_upload(&self, pre: &UploadPreData, etags: &[String]) -> Result<()> {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        use md5::{Digest, Md5};
+
+        debug!(task_id = %pre.task_id, parts = etags.len(), "commit upload");
+        let mut body = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<CompleteMultipartUpload>\n");
+        for (i, etag) in etags.iter().enumerate() {
+            body.push_str(&format!(
+                "<Part>\n<PartNumber>{}</PartNumber>\n<ETag>{}</ETag>\n</Part>\n",
+                i + 1,
+                etag
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let content_md5 = STANDARD.encode(Md5::digest(body.as_bytes()));
+        let callback = STANDARD.encode(serde_json::to_vec(&pre.callback)?);
+        let oss_date = httpdate::fmt_http_date(SystemTime::now());
+        let auth_meta = format!(
+            "POST\n{}\napplication/xml\n{}\nx-oss-callback:{}\nx-oss-date:{}\nx-oss-user-agent:{}\n/{}/{}?uploadId={}",
+            content_md5, oss_date, callback, oss_date, OSS_USER_AGENT, pre.bucket, pre.obj_key, pre.upload_id
+        );
+        let auth_key = self.upload_auth(pre, auth_meta).await?;
+        let url = format!("{}?uploadId={}", Self::oss_object_url(pre), pre.upload_id);
+        self.client
+            .post(url)
+            .header("Authorization", auth_key)
+            .header("Content-MD5", content_md5)
+            .header("Content-Type", "application/xml")
+            .header("x-oss-callback", callback)
+            .header("x-oss-date", oss_date)
+            .header("x-oss-user-agent", OSS_USER_AGENT)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Abort a multipart upload so the parts uploaded so far are discarded
+    pub async fn abort_upload(&self, pre: &UploadPreData) -> Result<()> {
+        debug!(task_id = %pre.task_id, "abort upload");
+        let oss_date = httpdate::fmt_http_date(SystemTime::now());
+        let auth_meta = format!(
+            "DELETE\n\n\n{}\nx-oss-date:{}\nx-oss-user-agent:{}\n/{}/{}?uploadId={}",
+            oss_date, oss_date, OSS_USER_AGENT, pre.bucket, pre.obj_key, pre.upload_id
+        );
+        let auth_key = self.upload_auth(pre, auth_meta).await?;
+        let url = format!("{}?uploadId={}", Self::oss_object_url(pre), pre.upload_id);
+        self.client
+            .delete(url)
+            .header("Authorization", auth_key)
+            .header("x-oss-date", oss_date)
+            .header("x-oss-user-agent", OSS_USER_AGENT)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn finish_upload(&self, pre: &UploadPreData) -> Result<()> {
+        debug!(task_id = %pre.task_id, "finish upload");
+        let req = UploadFinishRequest {
+            obj_key: pre.obj_key.clone(),
+            task_id: pre.task_id.clone(),
+        };
+        let _res: UploadFinishResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/upload/finish?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        Ok(())
+    }
+
+}
+
+/// Timeouts, rate limits and gateway errors are worth another try
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+async fn parse_response<U>(url: &reqwest::Url, res: reqwest::Response) -> Result<Option<U>>
+where
+    U: DeserializeOwned,
+{
+    let status = res.status();
+    if !status.is_success() {
+        let err_msg = res.text().await.unwrap_or_default();
+        debug!(status = %status, error = %err_msg, url = %url, "request failed");
+        return Err(QuarkError::from_response(status, &err_msg).into());
+    }
+    if status == StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    let res = res.json::<U>().await.map_err(QuarkError::from)?;
+    Ok(Some(res))
 }
 
 #[cfg(test)]
@@ -309,10 +785,12 @@ mod tests {
     use super::*;
 
     #[tokio::test]
+    #[ignore = "needs QUARK_COOKIE"]
     async fn test_get_files_by_pdir_fid() {
         let config = DriveConfig {
             api_base_url: "https://drive.quark.cn".to_string(),
             cookie: Some(std::env::var("QUARK_COOKIE").unwrap()),
+            state_file: None,
         };
         let drive = QuarkDrive::new(config).unwrap();
         let (files, _total) = drive.get_files_by_pdir_fid("0", 1, 50).await.unwrap();
@@ -322,10 +800,12 @@ mod tests {
 
 
     #[tokio::test]
+    #[ignore = "needs QUARK_COOKIE"]
     async fn test_get_download_urls() {
         let config = DriveConfig {
             api_base_url: "https://drive.quark.cn".to_string(),
             cookie: Some(std::env::var("QUARK_COOKIE").unwrap()),
+            state_file: None,
         };
         let drive = QuarkDrive::new(config).unwrap();
         let fids = vec!["your fid".to_string()];
@@ -335,10 +815,12 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "needs QUARK_COOKIE"]
     async fn test_download() {
         let config = DriveConfig {
             api_base_url: "https://drive.quark.cn".to_string(),
             cookie: Some(std::env::var("QUARK_COOKIE").unwrap()),
+            state_file: None,
         };
         let drive = QuarkDrive::new(config).unwrap();
         let url = "";
@@ -36,6 +36,23 @@ impl QuarkFile {
             download_url: None,
         }
     }
+
+    pub fn new_uploading(file_name: String, pdir_fid: String, size: u64) -> Self {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        Self {
+            pdir_fid,
+            size,
+            format_type: "".to_string(),
+            status: 1u8,
+            created_at: now,
+            updated_at: now,
+            dir: false,
+            file: true,
+            file_name,
+            fid: "".to_string(),
+            download_url: None,
+        }
+    }
 }
 
 
@@ -44,6 +61,7 @@ pub struct GetFilesDownloadUrlsRequest {
     pub fids: Vec<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct GetFileItem {
     pub fid: String,
@@ -125,3 +143,208 @@ impl From<GetFilesResponse> for QuarkFiles {
 }
 
 
+
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UploadPreRequest {
+    pub ccp_hash_update: bool,
+    pub dir_name: String,
+    pub file_name: String,
+    pub format_type: String,
+    pub l_created_at: u64,
+    pub l_updated_at: u64,
+    pub pdir_fid: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadPreData {
+    pub task_id: String,
+    #[serde(default)]
+    pub finish: bool,
+    pub upload_id: String,
+    pub obj_key: String,
+    pub upload_url: String,
+    pub fid: String,
+    pub bucket: String,
+    pub callback: UploadCallback,
+    #[serde(default)]
+    pub format_type: String,
+    pub auth_info: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadCallback {
+    #[serde(rename = "callbackUrl")]
+    pub callback_url: String,
+    #[serde(rename = "callbackBody")]
+    pub callback_body: String,
+}
+
+pub type UploadPreResponse = Response<UploadPreData, EmptyMetadata>;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UploadHashRequest {
+    pub md5: String,
+    pub sha1: String,
+    pub task_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadHashData {
+    #[serde(default)]
+    pub finish: bool,
+}
+
+pub type UploadHashResponse = Response<UploadHashData, EmptyMetadata>;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UploadAuthRequest {
+    pub auth_info: String,
+    pub auth_meta: String,
+    pub task_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadAuthData {
+    pub auth_key: String,
+}
+
+pub type UploadAuthResponse = Response<UploadAuthData, EmptyMetadata>;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UploadFinishRequest {
+    pub obj_key: String,
+    pub task_id: String,
+}
+
+pub type UploadFinishResponse = Response<serde_json::Value, EmptyMetadata>;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CreateFolderRequest {
+    pub dir_init_lock: bool,
+    pub dir_path: String,
+    pub file_name: String,
+    pub pdir_fid: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateFolderData {
+    pub fid: String,
+}
+
+pub type CreateFolderResponse = Response<CreateFolderData, EmptyMetadata>;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DeleteFilesRequest {
+    pub action_type: u8,
+    pub filelist: Vec<String>,
+    pub exclude_fids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaskData {
+    pub task_id: String,
+    #[serde(default)]
+    pub finish: bool,
+}
+
+pub type TaskResponse = Response<TaskData, EmptyMetadata>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaskStatusData {
+    pub status: u8,
+    #[serde(default)]
+    pub save_as: Option<TaskSaveAs>,
+}
+
+/// The files created by a copy task
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaskSaveAs {
+    #[serde(default)]
+    pub save_as_top_fids: Vec<String>,
+}
+
+pub type TaskStatusResponse = Response<TaskStatusData, EmptyMetadata>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecycleItem {
+    pub record_id: String,
+    pub fid: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecycleListData {
+    pub list: Vec<RecycleItem>,
+}
+
+pub type RecycleListResponse = Response<RecycleListData, EmptyMetadata>;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RecycleRemoveRequest {
+    pub select_mode: u8,
+    pub record_list: Vec<String>,
+}
+
+pub type RecycleRemoveResponse = Response<serde_json::Value, EmptyMetadata>;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RenameFileRequest {
+    pub fid: String,
+    pub file_name: String,
+}
+
+pub type RenameFileResponse = Response<serde_json::Value, EmptyMetadata>;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CopyFilesRequest {
+    pub action_type: u8,
+    pub exclude_fids: Vec<String>,
+    pub filelist: Vec<String>,
+    pub to_pdir_fid: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct MoveFilesRequest {
+    pub action_type: u8,
+    pub exclude_fids: Vec<String>,
+    pub filelist: Vec<String>,
+    pub to_pdir_fid: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemberData {
+    #[serde(default)]
+    pub total_capacity: u64,
+    #[serde(default)]
+    pub use_capacity: u64,
+}
+
+pub type MemberResponse = Response<MemberData, EmptyMetadata>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmptyMetadata {
+
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QrLoginResponse {
+    pub status: u32,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub data: Option<QrLoginData>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QrLoginData {
+    pub members: QrLoginMembers,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QrLoginMembers {
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub service_ticket: Option<String>,
+}
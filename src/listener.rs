@@ -0,0 +1,282 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use {
+    std::env,
+    std::net::{IpAddr, Ipv4Addr},
+    std::os::fd::{FromRawFd, IntoRawFd, RawFd},
+    std::os::unix::fs::FileTypeExt,
+    tokio::net::UnixListener,
+};
+
+#[cfg(unix)]
+/// Unix socket peers have no IP address, they all share this one for brute-force tracking
+const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// A stream accepted from any kind of listener
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection for T {}
+
+/// An address given by `--listen`, either `host:port` or `unix:/path/to/socket`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn parse(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        let addr = s
+            .to_socket_addrs()
+            .with_context(|| format!("invalid listen address {}", s))?
+            .next()
+            .with_context(|| format!("listen address {} did not resolve", s))?;
+        Ok(ListenAddr::Tcp(addr))
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        /// Socket file to remove on drop, `None` for inherited sockets
+        path: Option<PathBuf>,
+    },
+}
+
+impl Listener {
+    /// Bind all addresses, an IPv6 wildcard is made IPv6-only when an IPv4 address uses the same port
+    pub fn bind_all(addrs: &[ListenAddr]) -> Result<Vec<Listener>> {
+        addrs
+            .iter()
+            .map(|addr| match addr {
+                ListenAddr::Tcp(sock_addr) => {
+                    let only_v6 = sock_addr.is_ipv6()
+                        && addrs.iter().any(|other| {
+                            matches!(other, ListenAddr::Tcp(o) if o.is_ipv4() && o.port() == sock_addr.port())
+                        });
+                    bind_tcp(*sock_addr, only_v6)
+                        .with_context(|| format!("failed to listen on {}", sock_addr))
+                }
+                ListenAddr::Unix(path) => {
+                    bind_unix(path).with_context(|| format!("failed to listen on unix:{}", path.display()))
+                }
+            })
+            .collect()
+    }
+
+    /// Sockets passed by systemd socket activation through `LISTEN_FDS`
+    #[cfg(unix)]
+    pub fn from_systemd() -> Result<Vec<Listener>> {
+        const SD_LISTEN_FDS_START: RawFd = 3;
+
+        let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+        let count = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|fds| fds.parse::<RawFd>().ok())
+            .unwrap_or(0);
+        if pid != Some(std::process::id()) || count <= 0 {
+            return Ok(Vec::new());
+        }
+        // don't pass the sockets on to child processes
+        unsafe {
+            env::remove_var("LISTEN_PID");
+            env::remove_var("LISTEN_FDS");
+            env::remove_var("LISTEN_FDNAMES");
+        }
+
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .map(|fd| {
+                // Safety: systemd hands the ownership of these descriptors over to us
+                let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                if tcp.local_addr().is_ok() {
+                    tcp.set_nonblocking(true)?;
+                    return Ok(Listener::Tcp(TcpListener::from_std(tcp)?));
+                }
+                let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+                unix.set_nonblocking(true)?;
+                Ok(Listener::Unix {
+                    listener: UnixListener::from_std(unix)?,
+                    path: None,
+                })
+            })
+            .collect::<io::Result<_>>()
+            .context("invalid socket passed by systemd")
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_systemd() -> Result<Vec<Listener>> {
+        Ok(Vec::new())
+    }
+
+    pub async fn accept(&self) -> io::Result<(Box<dyn Connection>, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                Ok((Box::new(stream), remote_addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), UNIX_PEER_ADDR))
+            }
+        }
+    }
+
+    pub fn url(&self, scheme: &str) -> String {
+        match self {
+            Listener::Tcp(_) => format!("{}://{}", scheme, self),
+            #[cfg(unix)]
+            Listener::Unix { .. } => format!("{} on {}", scheme, self),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => match listener.local_addr().ok().and_then(|addr| {
+                addr.as_pathname().map(|path| path.display().to_string())
+            }) {
+                Some(path) => write!(f, "unix:{}", path),
+                None => write!(f, "unix"),
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { path: Some(path), .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> io::Result<Listener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &PathBuf) -> io::Result<Listener> {
+    // remove the socket left behind by an unclean exit
+    if let Ok(metadata) = std::fs::symlink_metadata(path)
+        && metadata.file_type().is_socket()
+    {
+        std::fs::remove_file(path)?;
+    }
+    Ok(Listener::Unix {
+        listener: UnixListener::bind(path)?,
+        path: Some(path.clone()),
+    })
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &PathBuf) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(
+            ListenAddr::parse("127.0.0.1:8080").unwrap(),
+            ListenAddr::Tcp("127.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(ListenAddr::parse("[::]:8080").unwrap(), ListenAddr::Tcp("[::]:8080".parse().unwrap()));
+        assert_eq!(
+            ListenAddr::parse("unix:/run/quarkdrive.sock").unwrap(),
+            ListenAddr::Unix(PathBuf::from("/run/quarkdrive.sock"))
+        );
+        assert!(ListenAddr::parse("8080").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listen_on_all_addresses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webdav.sock");
+        // left behind by an unclean exit
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listeners = Listener::bind_all(&[
+            ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()),
+            ListenAddr::Unix(path.clone()),
+        ])
+        .unwrap();
+        assert_eq!(listeners[1].to_string(), format!("unix:{}", path.display()));
+
+        let Listener::Tcp(tcp) = &listeners[0] else {
+            panic!("expected a TCP listener");
+        };
+        let mut client = tokio::net::TcpStream::connect(tcp.local_addr().unwrap()).await.unwrap();
+        let (mut conn, remote_addr) = listeners[0].accept().await.unwrap();
+        assert_eq!(remote_addr, client.local_addr().unwrap());
+        client.write_all(b"tcp").await.unwrap();
+        let mut buf = [0u8; 3];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"tcp");
+
+        let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (mut conn, remote_addr) = listeners[1].accept().await.unwrap();
+        assert_eq!(remote_addr, UNIX_PEER_ADDR);
+        client.write_all(b"unix").await.unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"unix");
+
+        drop(listeners);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_systemd_sockets_of_other_processes() {
+        // the variables are meant for another process, leave them alone
+        unsafe {
+            env::set_var("LISTEN_PID", "1");
+            env::set_var("LISTEN_FDS", "1");
+        }
+        assert!(Listener::from_systemd().unwrap().is_empty());
+        assert_eq!(env::var("LISTEN_FDS").unwrap(), "1");
+        unsafe {
+            env::remove_var("LISTEN_PID");
+            env::remove_var("LISTEN_FDS");
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use dav_server::davpath::DavPath;
+use dav_server::ls::{DavLock, DavLockSystem, LsFuture};
+use futures_util::FutureExt;
+use xmltree::Element;
+
+/// A view of a shared lock system for a user confined to `root`
+///
+/// Every user handler sees paths relative to its own root, the locks are kept under the
+/// full path so a lock taken by one user also holds for every other user of the same file.
+#[derive(Debug, Clone)]
+pub struct ScopedLs {
+    inner: Box<dyn DavLockSystem>,
+    /// URL encoded root without a trailing slash, empty for the top-level folder
+    root: String,
+}
+
+impl ScopedLs {
+    pub fn new(inner: Box<dyn DavLockSystem>, root: &str) -> Box<ScopedLs> {
+        let root = root
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| format!("/{}", encode_segment(segment)))
+            .collect();
+        Box::new(ScopedLs { inner, root })
+    }
+
+    /// The full path of a path below the root
+    fn full_path(&self, path: &DavPath) -> DavPath {
+        DavPath::new(&format!("{}{}", self.root, path.as_url_string())).unwrap_or_else(|_| path.clone())
+    }
+
+    /// Translate a lock back below the root, keeping the URL prefix of the request
+    fn scoped_lock(&self, mut lock: DavLock, path: &DavPath) -> DavLock {
+        let full = lock.path.as_url_string();
+        // a lock on a parent outside of the root covers the whole view
+        let rel = full.strip_prefix(&self.root).filter(|rel| rel.starts_with('/')).unwrap_or("/");
+        let prefix = path.prefix();
+        if let Ok(mut rel_path) = DavPath::new(&format!("{}{}", prefix, rel))
+            && (prefix.is_empty() || rel_path.set_prefix(prefix).is_ok())
+        {
+            lock.path = rel_path;
+        }
+        lock
+    }
+}
+
+impl DavLockSystem for ScopedLs {
+    fn lock(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        owner: Option<&Element>,
+        timeout: Option<Duration>,
+        shared: bool,
+        deep: bool,
+    ) -> LsFuture<'_, Result<DavLock, DavLock>> {
+        let path = path.clone();
+        let owner = owner.cloned();
+        let principal = principal.map(|principal| principal.to_string());
+        async move {
+            self.inner
+                .lock(
+                    &self.full_path(&path),
+                    principal.as_deref(),
+                    owner.as_ref(),
+                    timeout,
+                    shared,
+                    deep,
+                )
+                .await
+                .map(|lock| self.scoped_lock(lock, &path))
+                .map_err(|lock| self.scoped_lock(lock, &path))
+        }
+        .boxed()
+    }
+
+    fn unlock(&self, path: &DavPath, token: &str) -> LsFuture<'_, Result<(), ()>> {
+        self.inner.unlock(&self.full_path(path), token)
+    }
+
+    fn refresh(&self, path: &DavPath, token: &str, timeout: Option<Duration>) -> LsFuture<'_, Result<DavLock, ()>> {
+        let path = path.clone();
+        let token = token.to_string();
+        async move {
+            self.inner
+                .refresh(&self.full_path(&path), &token, timeout)
+                .await
+                .map(|lock| self.scoped_lock(lock, &path))
+        }
+        .boxed()
+    }
+
+    fn check(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        deep: bool,
+        submitted_tokens: Vec<&str>,
+    ) -> LsFuture<'_, Result<(), DavLock>> {
+        let path = path.clone();
+        let principal = principal.map(|principal| principal.to_string());
+        let submitted_tokens: Vec<String> = submitted_tokens.into_iter().map(|token| token.to_string()).collect();
+        async move {
+            let tokens = submitted_tokens.iter().map(|token| token.as_str()).collect();
+            self.inner
+                .check(&self.full_path(&path), principal.as_deref(), ignore_principal, deep, tokens)
+                .await
+                .map_err(|lock| self.scoped_lock(lock, &path))
+        }
+        .boxed()
+    }
+
+    fn discover(&self, path: &DavPath) -> LsFuture<'_, Vec<DavLock>> {
+        let path = path.clone();
+        async move {
+            self.inner
+                .discover(&self.full_path(&path))
+                .await
+                .into_iter()
+                .map(|lock| self.scoped_lock(lock, &path))
+                .collect()
+        }
+        .boxed()
+    }
+
+    fn delete(&self, path: &DavPath) -> LsFuture<'_, Result<(), ()>> {
+        self.inner.delete(&self.full_path(path))
+    }
+}
+
+fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use dav_server::memls::MemLs;
+
+    use super::*;
+
+    fn path(path: &str) -> DavPath {
+        DavPath::new(path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_scoped_locks_are_shared() {
+        let shared = MemLs::new();
+        let alice = ScopedLs::new(shared.clone(), "/alice docs/");
+        let admin = ScopedLs::new(shared.clone(), "/");
+        let bob = ScopedLs::new(shared, "bob");
+
+        let lock = alice
+            .lock(&path("/a.txt"), Some("alice"), None, None, false, false)
+            .await
+            .unwrap();
+        assert_eq!(lock.path.as_url_string(), "/a.txt");
+
+        let conflict = admin
+            .check(&path("/alice%20docs/a.txt"), Some("admin"), false, false, Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(conflict.token, lock.token);
+        assert_eq!(conflict.path.as_url_string(), "/alice%20docs/a.txt");
+        assert!(bob
+            .check(&path("/a.txt"), Some("bob"), false, false, Vec::new())
+            .await
+            .is_ok());
+
+        // a deep lock on a folder above a root covers the whole view
+        let deep = admin
+            .lock(&path("/bob/"), Some("admin"), None, None, false, true)
+            .await
+            .unwrap();
+        let conflict = bob
+            .check(&path("/b.txt"), Some("bob"), false, false, Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(conflict.token, deep.token);
+        assert_eq!(conflict.path.as_url_string(), "/");
+
+        alice.unlock(&path("/a.txt"), &lock.token).await.unwrap();
+        assert!(admin.discover(&path("/alice%20docs/a.txt")).await.is_empty());
+    }
+}
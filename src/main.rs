@@ -1,104 +1,185 @@
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::path::PathBuf;
-use std::sync::{Arc};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::time::Duration;
-use anyhow::bail;
-use clap::{Parser, Subcommand};
-use dav_server::{memls::MemLs, DavHandler};
+use anyhow::{bail, Context};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use dav_server::{fs::GuardedFileSystem, ls::DavLockSystem, memls::MemLs, DavHandler};
 #[cfg(unix)]
 use futures_util::stream::StreamExt;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 #[cfg(unix)]
-use {signal_hook::consts::signal::*, signal_hook_tokio::Signals};
+use {signal_hook::consts::signal::*, signal_hook_tokio::Signals, tokio::sync::oneshot};
 
+use admin::AdminServer;
+use auth::{AuthScheme, HashAlgorithm, LoginGuard, LoginGuardConfig, Password, UserConfig};
 use cache::Cache;
+use config::FileConfig;
+use listener::{ListenAddr, Listener};
+use lock::ScopedLs;
 use drive::*;
-use vfs::QuarkDriveFileSystem;
-use webdav::WebDavServer;
+use drive::login::QrLogin;
+use vfs::{AccountsFileSystem, QuarkDriveFileSystem, RootFileSystem};
+use webdav::{Connections, TlsConfig, WebDavServer, WebDavUser};
 
+mod admin;
+mod auth;
 mod cache;
+mod config;
 mod drive;
+mod listener;
+mod lock;
+mod stats;
+mod upgrade;
 mod vfs;
 mod webdav;
 
 use tokio::time::interval;
 
+const STATE_FILE: &str = "state.json";
+const QUARK_API_BASE_URL: &str = "https://drive.quark.cn";
+
 #[derive(Parser, Debug)]
 #[command(name = "quarkdrive-webdav", about, version, author)]
-#[command(args_conflicts_with_subcommands = true)]
 struct Opt {
+    /// TOML configuration file, options given on the command line or in the environment take precedence
+    #[arg(short, long, env = "QUARKDRIVE_CONFIG", global = true)]
+    config: Option<PathBuf>,
     /// Listen host
-    #[arg(long, env = "HOST", default_value = "0.0.0.0")]
+    #[arg(long, env = "HOST", default_value = "0.0.0.0", global = true)]
     host: String,
     /// Listen port
-    #[arg(short, env = "PORT", default_value = "8080")]
+    #[arg(short, env = "PORT", default_value = "8080", global = true)]
     port: u16,
+    /// Listen addresses such as `0.0.0.0:8080`, `[::]:8080` or `unix:/run/quarkdrive.sock`, overrides host and port
+    #[arg(long, env = "LISTEN", value_delimiter = ',', global = true)]
+    listen: Vec<String>,
 
     ///  drive client_secret
-    #[arg(long, env = "QUARK_COOKIE")]
+    #[arg(long, env = "QUARK_COOKIE", global = true)]
     quark_cookie: Option<String>,
+    /// Directory of the state file keeping the refreshed cookie, defaults to the user config directory
+    #[arg(long, env = "QUARK_STATE_DIR", global = true)]
+    state_dir: Option<PathBuf>,
 
     /// WebDAV authentication username
-    #[arg(short = 'U', long, env = "WEBDAV_AUTH_USER")]
+    #[arg(short = 'U', long, env = "WEBDAV_AUTH_USER", global = true)]
     auth_user: Option<String>,
-    /// WebDAV authentication password
-    #[arg(short = 'W', long, env = "WEBDAV_AUTH_PASSWORD")]
+    /// WebDAV authentication password, plain text or an argon2/bcrypt hash
+    #[arg(short = 'W', long, env = "WEBDAV_AUTH_PASSWORD", global = true)]
     auth_password: Option<String>,
+    /// WebDAV authentication scheme, digest requires plain text passwords
+    #[arg(long, env = "WEBDAV_AUTH_SCHEME", value_enum, default_value = "basic", global = true)]
+    auth_scheme: AuthScheme,
+    /// Failed logins from one address before it is banned
+    #[arg(long, env = "AUTH_MAX_FAILURES", default_value = "10", global = true)]
+    auth_max_failures: u32,
+    /// Back-off in seconds after a failed login, doubled for every further failure
+    #[arg(long, env = "AUTH_BACKOFF_SECS", default_value = "1", global = true)]
+    auth_backoff_secs: u64,
+    /// Ban duration in seconds after too many failed logins
+    #[arg(long, env = "AUTH_BAN_SECS", default_value = "3600", global = true)]
+    auth_ban_secs: u64,
+    /// Reverse proxy addresses whose `X-Forwarded-For` or `Forwarded` header names the client,
+    /// failed logins count against that client instead of the proxy
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',', global = true)]
+    trusted_proxies: Vec<IpAddr>,
+    /// JSON file with Quark accounts, each with a name, cookie, root, read_only and no_trash flag,
+    /// served as top-level folders instead of QUARK_COOKIE
+    #[arg(long, env = "QUARK_ACCOUNTS_FILE", global = true)]
+    accounts_file: Option<PathBuf>,
+    /// JSON file with WebDAV users, each with a username, password, root and read_only flag
+    #[arg(long, env = "WEBDAV_USERS_FILE", global = true)]
+    users_file: Option<PathBuf>,
     /// Automatically generate index.html
-    #[arg(short = 'I', long)]
+    #[arg(short = 'I', long, env = "AUTO_INDEX", global = true)]
     auto_index: bool,
     /// Read/download buffer size in bytes, defaults to 10MB
-    #[arg(short = 'S', long, default_value = "10485760")]
+    #[arg(short = 'S', long, env = "READ_BUFFER_SIZE", default_value = "10485760", global = true)]
     read_buffer_size: usize,
     /// Upload buffer size in bytes, defaults to 16MB
-    #[arg(long, default_value = "16777216")]
+    #[arg(long, env = "UPLOAD_BUFFER_SIZE", default_value = "16777216", global = true)]
     upload_buffer_size: usize,
     /// Directory entries cache size
-    #[arg(long, default_value = "1000")]
+    #[arg(long, env = "CACHE_SIZE", default_value = "1000", global = true)]
     cache_size: u64,
     /// Directory entries cache expiration time in seconds
-    #[arg(long, default_value = "600")]
+    #[arg(long, env = "CACHE_TTL", default_value = "600", global = true)]
     cache_ttl: u64,
     /// Root directory path
-    #[arg(long, env = "WEBDAV_ROOT", default_value = "/")]
+    #[arg(long, env = "WEBDAV_ROOT", default_value = "/", global = true)]
     root: String,
     /// Delete file permanently instead of trashing it
-    #[arg(long)]
+    #[arg(long, env = "NO_TRASH", global = true)]
     no_trash: bool,
     /// Enable read only mode
-    #[arg(long)]
+    #[arg(long, env = "READ_ONLY", global = true)]
     read_only: bool,
     /// TLS certificate file path
-    #[arg(long, env = "TLS_CERT")]
+    #[arg(long, env = "TLS_CERT", global = true)]
     tls_cert: Option<PathBuf>,
     /// TLS private key file path
-    #[arg(long, env = "TLS_KEY")]
+    #[arg(long, env = "TLS_KEY", global = true)]
     tls_key: Option<PathBuf>,
     /// Prefix to be stripped off when handling request.
-    #[arg(long, env = "WEBDAV_STRIP_PREFIX")]
+    #[arg(long, env = "WEBDAV_STRIP_PREFIX", global = true)]
     strip_prefix: Option<String>,
     /// Enable debug log
-    #[arg(long)]
+    #[arg(long, global = true)]
     debug: bool,
-    /// Disable self auto upgrade
-    #[arg(long)]
+    /// Disable the startup check for new releases and the `upgrade` subcommand
+    #[arg(long, env = "NO_SELF_UPGRADE", global = true)]
     no_self_upgrade: bool,
+    /// Install a new release found by the startup check, used after a restart, otherwise it is only logged
+    #[arg(long, env = "AUTO_UPGRADE", global = true)]
+    auto_upgrade: bool,
+    /// GitHub API base URL to look for releases
+    #[arg(long, env = "UPGRADE_URL", default_value = upgrade::GITHUB_API_URL, global = true)]
+    upgrade_url: String,
     /// Skip uploading same size file
-    #[arg(long)]
+    #[arg(long, env = "SKIP_UPLOAD_SAME_SIZE", global = true)]
     skip_upload_same_size: bool,
     /// Prefer downloading using HTTP protocol
-    #[arg(long)]
+    #[arg(long, env = "PREFER_HTTP_DOWNLOAD", global = true)]
     prefer_http_download: bool,
     /// Enable 302 redirect when possible
-    #[arg(long)]
+    #[arg(long, env = "REDIRECT", global = true)]
     redirect: bool,
 
     #[command(subcommand)]
     subcommands: Option<Commands>,
 
-    #[arg(long, env = "REFRESH_CACHE_SECS_INTERVAL", default_value = "300")]
+    #[arg(long, env = "REFRESH_CACHE_SECS_INTERVAL", default_value = "300", global = true)]
     refresh_cache_secs_interval: u64,
+
+    /// Serve Prometheus metrics on this address, such as `127.0.0.1:9090`, along with the health probes
+    #[arg(long, env = "METRICS_LISTEN", global = true)]
+    metrics_listen: Option<SocketAddr>,
+    /// Serve only the `/healthz` and `/readyz` probes on this address, such as `127.0.0.1:8081`
+    #[arg(long, env = "HEALTH_LISTEN", global = true)]
+    health_listen: Option<SocketAddr>,
+
+    /// Serve the admin API on this address, such as `127.0.0.1:9091`
+    #[arg(long, env = "ADMIN_LISTEN", global = true)]
+    admin_listen: Option<SocketAddr>,
+    /// Bearer token required by the admin API
+    #[arg(long, env = "ADMIN_TOKEN", global = true)]
+    admin_token: Option<String>,
+
+    /// Seconds to wait for active connections to finish on SIGTERM/SIGINT
+    #[arg(long, env = "SHUTDOWN_TIMEOUT", default_value = "30", global = true)]
+    shutdown_timeout: u64,
+
+    /// WebDAV users from the configuration file
+    #[arg(skip)]
+    users: Vec<UserConfig>,
+    /// Quark accounts from the configuration file
+    #[arg(skip)]
+    accounts: Vec<AccountConfig>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -106,6 +187,25 @@ enum Commands {
     /// Scan QRCode
     #[command(subcommand)]
     Qr(QrCommand),
+    /// Hash a password for WEBDAV_AUTH_PASSWORD or the users file
+    HashPassword {
+        /// Hash algorithm
+        #[arg(long, value_enum, default_value = "argon2")]
+        algorithm: HashAlgorithm,
+        /// Password to hash, read from stdin if not given
+        password: Option<String>,
+    },
+    /// Configuration file tools
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Upgrade to the latest release
+    Upgrade,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Validate the configuration and print the effective settings with secrets redacted
+    Check,
 }
 
 #[derive(Subcommand, Debug)]
@@ -117,12 +217,123 @@ enum QrCommand {
     /// Query the QRCode login result
     #[command(arg_required_else_help = true)]
     Query {
-        /// Query parameter sid
+        /// Query parameter sid, the token printed by `qr generate`
         #[arg(long)]
         sid: String,
     },
 }
 
+async fn qr_login(command: &QrCommand, state_file: Option<&Path>) -> anyhow::Result<()> {
+    let login = QrLogin::new()?;
+    match command {
+        QrCommand::Generate => {
+            let token = login.generate().await?;
+            print_qrcode(&token)?;
+            println!("sid: {}", token);
+        }
+        QrCommand::Query { sid } => {
+            let cookie = login.wait_for_cookie(sid).await?;
+            save_login_cookie(&cookie, state_file)?;
+        }
+        QrCommand::Login => {
+            let token = login.generate().await?;
+            print_qrcode(&token)?;
+            println!("Scan the QR code with the Quark app and confirm the login");
+            let cookie = login.wait_for_cookie(&token).await?;
+            save_login_cookie(&cookie, state_file)?;
+        }
+    }
+    Ok(())
+}
+
+fn save_login_cookie(cookie: &str, state_file: Option<&Path>) -> anyhow::Result<()> {
+    println!("QUARK_COOKIE={}", cookie);
+    if let Some(state_file) = state_file {
+        drive::cookie::save_login_cookie(state_file, cookie)?;
+        println!("cookie saved to {}, used when QUARK_COOKIE is not set", state_file.display());
+    }
+    Ok(())
+}
+
+fn self_upgrade(upgrade_url: &str) -> anyhow::Result<()> {
+    let Some(release) = upgrade::check(upgrade_url)? else {
+        println!("already up to date, version {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    };
+    println!("upgrading from {} to {}", env!("CARGO_PKG_VERSION"), release.version);
+    upgrade::upgrade(&release)?;
+    println!("upgraded to {}, restart to use the new version", release.version);
+    Ok(())
+}
+
+fn print_qrcode(token: &str) -> anyhow::Result<()> {
+    use qrcode::render::unicode::Dense1x2;
+
+    let url = QrLogin::qrcode_url(token);
+    let image = qrcode::QrCode::new(&url)?
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+    println!("{}", image);
+    println!("{}", url);
+    Ok(())
+}
+
+/// Validate the merged options, returns the WebDAV users and Quark accounts from all sources
+fn check_config(opt: &Opt) -> anyhow::Result<(Vec<UserConfig>, Vec<AccountConfig>)> {
+    if opt.auth_user.is_some() != opt.auth_password.is_some() {
+        bail!("auth-user and auth-password must be specified together.");
+    }
+    if opt.tls_cert.is_some() != opt.tls_key.is_some() {
+        bail!("tls-cert and tls-key must be specified together.");
+    }
+    if opt.admin_listen.is_some() && opt.admin_token.as_deref().is_none_or(str::is_empty) {
+        bail!("admin-listen requires an admin-token.");
+    }
+    for addr in &opt.listen {
+        ListenAddr::parse(addr)?;
+    }
+
+    let mut users = opt.users.clone();
+    if let Some(path) = &opt.users_file {
+        users.extend(auth::load_users(path)?);
+    }
+    let mut passwords = Vec::new();
+    if let (Some(auth_user), Some(auth_password)) = (&opt.auth_user, &opt.auth_password) {
+        passwords.push((auth_user, auth_password));
+    }
+    passwords.extend(users.iter().map(|user| (&user.username, &user.password)));
+    for (i, (username, password)) in passwords.iter().enumerate() {
+        if passwords[..i].iter().any(|(other, _)| other == username) {
+            bail!("user {} is defined more than once", username);
+        }
+        if opt.auth_scheme == AuthScheme::Digest && Password::parse(password).plain().is_none() {
+            bail!("digest authentication requires a plain text password for user {}.", username);
+        }
+    }
+
+    let mut accounts = opt.accounts.clone();
+    if let Some(path) = &opt.accounts_file {
+        accounts.extend(load_accounts(path)?);
+    }
+    validate_accounts(&accounts)?;
+    Ok((users, accounts))
+}
+
+fn build_dav_handler(fs: Box<dyn GuardedFileSystem<()>>, locks: Box<dyn DavLockSystem>, opt: &Opt) -> DavHandler {
+    let mut dav_server_builder = DavHandler::builder()
+        .filesystem(fs)
+        .locksystem(locks)
+        .read_buf_size(opt.read_buffer_size)
+        .autoindex(opt.auto_index)
+        .redirect(opt.redirect);
+    if let Some(prefix) = &opt.strip_prefix {
+        dav_server_builder = dav_server_builder.strip_prefix(prefix.clone());
+    }
+    dav_server_builder.build_handler()
+}
+
 pub fn start_periodic_invalidate(cache: Arc<Cache>, secs: u64) {
     tokio::spawn(async move {
         let mut ticker = interval(Duration::from_secs(secs));
@@ -137,7 +348,23 @@ pub fn start_periodic_invalidate(cache: Arc<Cache>, secs: u64) {
 async fn main() -> anyhow::Result<()> {
     #[cfg(feature = "native-tls-vendored")]
     openssl_probe::init_openssl_env_vars();
-    let opt = Opt::parse();
+    let matches = Opt::command().get_matches();
+    let mut opt = Opt::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if let Some(path) = opt.config.clone() {
+        FileConfig::load(&path)?.apply(&mut opt, &matches);
+    }
+    if let Some(Commands::HashPassword { algorithm, password }) = &opt.subcommands {
+        let password = match password {
+            Some(password) => password.clone(),
+            None => {
+                let mut password = String::new();
+                std::io::stdin().read_line(&mut password)?;
+                password.trim_end_matches(['\r', '\n']).to_string()
+            }
+        };
+        println!("{}", auth::hash_password(&password, *algorithm)?);
+        return Ok(());
+    }
     if env::var("RUST_LOG").is_err() {
         if opt.debug {
             unsafe { env::set_var("RUST_LOG", "quarkdrive_webdav=debug,reqwest=debug"); }
@@ -150,83 +377,232 @@ async fn main() -> anyhow::Result<()> {
         .with_timer(tracing_subscriber::fmt::time::time())
         .init();
 
-    let drive_config = DriveConfig {
-        api_base_url: "https://drive.quark.cn".to_string(),
-        cookie: opt.quark_cookie.clone(),
+    let state_file = opt
+        .state_dir
+        .clone()
+        .or_else(|| dirs::config_dir().map(|dir| dir.join("quarkdrive-webdav")))
+        .map(|dir| dir.join(STATE_FILE));
+    if let Some(Commands::Qr(command)) = &opt.subcommands {
+        return qr_login(command, state_file.as_deref()).await;
+    }
+    if let Some(Commands::Upgrade) = &opt.subcommands {
+        if opt.no_self_upgrade {
+            bail!("self upgrade is disabled by --no-self-upgrade.");
+        }
+        let upgrade_url = opt.upgrade_url.clone();
+        return tokio::task::spawn_blocking(move || self_upgrade(&upgrade_url)).await?;
+    }
+
+    let (users_config, accounts_config) = check_config(&opt)?;
+    let tls_config = match (opt.tls_cert.clone(), opt.tls_key.clone()) {
+        (Some(cert), Some(key)) => Some(TlsConfig::new(cert, key)?),
+        _ => None,
     };
-    let auth_user = opt.auth_user;
-    let auth_password = opt.auth_password;
-    if (auth_user.is_some() && auth_password.is_none())
-        || (auth_user.is_none() && auth_password.is_some())
-    {
-        bail!("auth-user and auth-password must be specified together.");
+    if let Some(Commands::Config(ConfigCommand::Check)) = &opt.subcommands {
+        print!("{}", FileConfig::redacted(&opt, &users_config, &accounts_config).to_toml()?);
+        return Ok(());
     }
 
-    let tls_config = match (opt.tls_cert, opt.tls_key) {
-        (Some(cert), Some(key)) => Some((cert, key)),
-        (None, None) => None,
-        _ => bail!("tls-cert and tls-key must be specified together."),
+    let redacted_config = serde_json::to_value(FileConfig::redacted(&opt, &users_config, &accounts_config))?;
+    let metrics = opt.metrics_listen.map(|_| stats::install()).transpose()?;
+
+    let mut listeners = Listener::from_systemd()?;
+    if opt.listen.is_empty() && listeners.is_empty() {
+        let addr = ListenAddr::parse(&format!("{}:{}", opt.host, opt.port))?;
+        listeners.extend(Listener::bind_all(&[addr])?);
+    } else {
+        let addrs = opt
+            .listen
+            .iter()
+            .map(|addr| ListenAddr::parse(addr))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        listeners.extend(Listener::bind_all(&addrs)?);
+    }
+
+    let read_only = Arc::new(AtomicBool::new(opt.read_only));
+    let new_fs = |drive: QuarkDrive, root: String| -> anyhow::Result<QuarkDriveFileSystem> {
+        let mut fs = QuarkDriveFileSystem::new(drive, root, opt.cache_size, opt.cache_ttl)?;
+        fs.set_no_trash(opt.no_trash)
+            .set_read_only_switch(read_only.clone())
+            .set_upload_buffer_size(opt.upload_buffer_size)
+            .set_skip_upload_same_size(opt.skip_upload_same_size)
+            .set_prefer_http_download(opt.prefer_http_download);
+        Ok(fs)
     };
-    let drive = QuarkDrive::new(drive_config)?;
-    let mut fs = QuarkDriveFileSystem::new(drive, opt.root, opt.cache_size, opt.cache_ttl)?;
-    fs.set_no_trash(opt.no_trash)
-        .set_read_only(opt.read_only)
-        .set_upload_buffer_size(opt.upload_buffer_size)
-        .set_skip_upload_same_size(opt.skip_upload_same_size)
-        .set_prefer_http_download(opt.prefer_http_download);
-    let cache = Arc::new(fs.dir_cache.clone());
-    start_periodic_invalidate(cache.clone(), opt.refresh_cache_secs_interval);
-    #[cfg(unix)]
-    let mut dav_server_builder = DavHandler::builder()
-        .filesystem(Box::new(fs))
-        .locksystem(MemLs::new())
-        .read_buf_size(opt.read_buffer_size)
-        .autoindex(opt.auto_index)
-        .redirect(opt.redirect);
-    if let Some(prefix) = opt.strip_prefix {
-        dav_server_builder = dav_server_builder.strip_prefix(prefix);
+    let mut drives = Vec::new();
+    let mut caches = Vec::new();
+    let fs = if accounts_config.is_empty() {
+        let drive = QuarkDrive::new(DriveConfig {
+            api_base_url: QUARK_API_BASE_URL.to_string(),
+            cookie: opt.quark_cookie.clone(),
+            state_file,
+        })?;
+        let fs = new_fs(drive.clone(), opt.root.clone())?;
+        drives.push(drive);
+        caches.push(Arc::new(fs.dir_cache.clone()));
+        RootFileSystem::Drive(Box::new(fs))
+    } else {
+        let mut accounts = BTreeMap::new();
+        for account in accounts_config {
+            let drive = QuarkDrive::new(DriveConfig {
+                api_base_url: QUARK_API_BASE_URL.to_string(),
+                cookie: account.cookie,
+                state_file: state_file
+                    .as_ref()
+                    .map(|file| file.with_file_name(format!("state-{}.json", account.name))),
+            })
+            .with_context(|| format!("account {}", account.name))?;
+            let mut fs = new_fs(drive.clone(), account.root.unwrap_or_else(|| opt.root.clone()))?;
+            fs.set_no_trash(opt.no_trash || account.no_trash)
+                .set_read_only(account.read_only);
+            drives.push(drive);
+            caches.push(Arc::new(fs.dir_cache.clone()));
+            accounts.insert(account.name, fs);
+        }
+        info!(accounts = ?accounts.keys().collect::<Vec<_>>(), "serving multiple accounts");
+        RootFileSystem::Accounts(AccountsFileSystem::new(accounts))
+    };
+    let health = stats::HealthCheck::new(drives.clone());
+    if let Some(addr) = opt.metrics_listen {
+        stats::serve(addr, metrics, health.clone()).await?;
+    }
+    if let Some(addr) = opt.health_listen {
+        stats::serve(addr, None, health).await?;
+    }
+    for cache in &caches {
+        start_periodic_invalidate(cache.clone(), opt.refresh_cache_secs_interval);
+    }
+
+    // one lock system for all users, so a lock holds whoever accesses the file
+    let locks = MemLs::new();
+    let mut users = HashMap::new();
+    if let (Some(auth_user), Some(auth_password)) = (opt.auth_user.clone(), opt.auth_password.clone()) {
+        users.insert(auth_user, WebDavUser {
+            password: Password::parse(&auth_password),
+            handler: build_dav_handler(fs.clone().boxed(), locks.clone(), &opt),
+        });
+    }
+    for user in users_config {
+        let user_fs = fs
+            .scoped(&user.root, user.read_only)
+            .with_context(|| format!("user {}", user.username))?;
+        users.insert(user.username, WebDavUser {
+            password: Password::parse(&user.password),
+            handler: build_dav_handler(user_fs, ScopedLs::new(locks.clone(), &user.root), &opt),
+        });
     }
 
-    let dav_server = dav_server_builder.build_handler();
+    let connections = Connections::default();
+    if let (Some(addr), Some(token)) = (opt.admin_listen, opt.admin_token.clone()) {
+        AdminServer {
+            token,
+            fs: fs.clone(),
+            connections: connections.clone(),
+            read_only: read_only.clone(),
+            config: redacted_config,
+        }
+        .start(addr)
+        .await?;
+    }
+
+    let dav_server = build_dav_handler(fs.boxed(), locks, &opt);
     debug!(
         read_buffer_size = opt.read_buffer_size,
         auto_index = opt.auto_index,
         "webdav handler initialized"
     );
 
+    if !opt.no_self_upgrade {
+        let upgrade_url = opt.upgrade_url.clone();
+        let auto_upgrade = opt.auto_upgrade;
+        tokio::task::spawn_blocking(move || match upgrade::check(&upgrade_url) {
+            Ok(Some(release)) if auto_upgrade => match upgrade::upgrade(&release) {
+                Ok(()) => info!(version = %release.version, "upgraded, restart to use the new version"),
+                Err(err) => error!(version = %release.version, error = %err, "upgrade failed"),
+            },
+            Ok(Some(release)) => info!(
+                version = %release.version,
+                "new version available, run `quarkdrive-webdav upgrade` or set --auto-upgrade to upgrade"
+            ),
+            Ok(None) => debug!("already up to date"),
+            Err(err) => warn!(error = %err, "check for upgrade failed"),
+        });
+    }
+
     let server = WebDavServer {
-        host: opt.host,
-        port: opt.port,
-        auth_user,
-        auth_password,
-        tls_config,
+        listeners,
+        users,
+        auth_scheme: opt.auth_scheme,
+        login_guard: LoginGuard::new(LoginGuardConfig {
+            max_failures: opt.auth_max_failures,
+            backoff: Duration::from_secs(opt.auth_backoff_secs),
+            ban: Duration::from_secs(opt.auth_ban_secs),
+            trusted_proxies: opt.trusted_proxies.clone(),
+        }),
+        tls_config: tls_config.clone(),
+        shutdown_timeout: Duration::from_secs(opt.shutdown_timeout),
+        connections,
         handler: dav_server,
     };
 
     #[cfg(not(unix))]
-    server.serve().await?;
+    server
+        .serve(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
     #[cfg(unix)]
     {
-        let signals = Signals::new([SIGHUP])?;
+        let signals = Signals::new([SIGHUP, SIGTERM, SIGINT])?;
         let handle = signals.handle();
-        let signals_task = tokio::spawn(handle_signals(signals, cache));
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let signals_task = tokio::spawn(handle_signals(signals, caches, tls_config, shutdown_tx));
 
-        server.serve().await?;
+        server
+            .serve(async {
+                let _ = shutdown_rx.await;
+            })
+            .await?;
 
         // Terminate the signal stream.
         handle.close();
         signals_task.await?;
     }
+    info!("server stopped");
     Ok(())
 }
 
 #[cfg(unix)]
-async fn handle_signals(mut signals: Signals, dir_cache: Arc<Cache>) {
+async fn handle_signals(
+    mut signals: Signals,
+    dir_caches: Vec<Arc<Cache>>,
+    tls_config: Option<TlsConfig>,
+    shutdown_tx: oneshot::Sender<()>,
+) {
+    let mut shutdown_tx = Some(shutdown_tx);
     while let Some(signal) = signals.next().await {
         match signal {
+            SIGTERM | SIGINT => match shutdown_tx.take() {
+                Some(tx) => {
+                    info!(signal = signal, "received shutdown signal");
+                    let _ = tx.send(());
+                }
+                None => {
+                    warn!(signal = signal, "received second shutdown signal, exiting immediately");
+                    std::process::exit(1);
+                }
+            },
             SIGHUP => {
-                dir_cache.invalidate_all();
+                for dir_cache in &dir_caches {
+                    dir_cache.invalidate_all();
+                }
                 info!("directory cache invalidated by SIGHUP");
+                if let Some(tls_config) = &tls_config {
+                    match tls_config.reload() {
+                        Ok(_) => info!("TLS certificate reloaded by SIGHUP"),
+                        Err(err) => error!(error = %err, "reload TLS certificate failed"),
+                    }
+                }
             }
             _ => unreachable!(),
         }
@@ -0,0 +1,262 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use dav_server::body::Body;
+use futures_util::future::try_join_all;
+use hyper::body::Incoming;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use moka::future::Cache as MokaCache;
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+use crate::drive::QuarkDrive;
+
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+/// Methods labelled by name, anything else is `other` so clients can't create unbounded label values
+const METHODS: &[&str] = &[
+    "GET", "HEAD", "PUT", "POST", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+    "PROPFIND", "PROPPATCH", "MKCOL", "COPY", "MOVE", "LOCK", "UNLOCK",
+];
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+const READY_CACHE_TTL: u64 = 5;
+
+/// Record metrics from now on, they are discarded when this is never called
+pub fn install() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets(LATENCY_BUCKETS)?
+        .install_recorder()?;
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(UPKEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    describe_counter!("webdav_requests_total", "WebDAV requests by method and status");
+    describe_histogram!("webdav_request_duration_seconds", Unit::Seconds, "WebDAV request latency by method and status");
+    describe_gauge!("webdav_active_connections", "Open client connections");
+    describe_counter!("quark_api_requests_total", "Quark API calls by endpoint");
+    describe_counter!("quark_api_errors_total", "Failed Quark API calls by endpoint");
+    describe_counter!("quark_api_retries_total", "Retried Quark API calls by endpoint");
+    describe_counter!("cache_hits_total", "Directory cache hits");
+    describe_counter!("cache_misses_total", "Directory cache misses");
+    describe_gauge!("cache_entries", "Cached directories");
+    describe_counter!("download_bytes_total", Unit::Bytes, "Bytes downloaded from Quark through the server");
+    Ok(handle)
+}
+
+/// Serve `/healthz` and `/readyz`, and `/metrics` when given, on `addr` in the background, returns the bound address
+///
+/// The probes live here rather than on the WebDAV listener so they never shadow files of the drive.
+pub async fn serve(addr: SocketAddr, metrics: Option<PrometheusHandle>, health: HealthCheck) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to listen on {}", addr))?;
+    let addr = listener.local_addr()?;
+    if metrics.is_some() {
+        info!("serving metrics on http://{}/metrics", addr);
+    } else {
+        info!("serving health probes on http://{}/readyz", addr);
+    }
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    error!(error = %err, "metrics accept failed");
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            let health = health.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| {
+                    let metrics = metrics.clone();
+                    let health = health.clone();
+                    async move { Ok::<_, Infallible>(handle(req, metrics.as_ref(), &health).await) }
+                });
+                let builder = auto::Builder::new(TokioExecutor::new());
+                if let Err(e) = builder.serve_connection(TokioIo::new(stream), service).await {
+                    error!("metrics serve error: {}", e);
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+async fn handle(req: Request<Incoming>, metrics: Option<&PrometheusHandle>, health: &HealthCheck) -> Response<Body> {
+    if !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return text(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed".to_string());
+    }
+    match req.uri().path() {
+        "/metrics" if let Some(metrics) = metrics => text(StatusCode::OK, metrics.render()),
+        "/healthz" => text(StatusCode::OK, "OK".to_string()),
+        "/readyz" => match health.check().await {
+            None => text(StatusCode::OK, "OK".to_string()),
+            Some(err) => text(StatusCode::SERVICE_UNAVAILABLE, err),
+        },
+        _ => text(StatusCode::NOT_FOUND, "Not Found".to_string()),
+    }
+}
+
+fn text(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Readiness of the Quark sessions, cached briefly so probes don't hammer the API
+#[derive(Clone)]
+pub struct HealthCheck {
+    drives: Vec<QuarkDrive>,
+    ready: MokaCache<(), Option<String>>,
+}
+
+impl HealthCheck {
+    pub fn new(drives: Vec<QuarkDrive>) -> Self {
+        Self {
+            drives,
+            ready: MokaCache::builder()
+                .time_to_live(Duration::from_secs(READY_CACHE_TTL))
+                .build(),
+        }
+    }
+
+    /// Returns the error message when any session is not usable
+    async fn check(&self) -> Option<String> {
+        let drives = self.drives.clone();
+        self.ready
+            .get_with((), async move {
+                match try_join_all(drives.iter().map(|drive| drive.check_session())).await {
+                    Ok(_) => None,
+                    Err(err) => {
+                        warn!(error = %err, "readiness check failed");
+                        Some(err.to_string())
+                    }
+                }
+            })
+            .await
+    }
+}
+
+pub fn record_request(method: &str, status: u16, elapsed: Duration) {
+    let method = METHODS.iter().find(|known| **known == method).copied().unwrap_or("other");
+    let labels = [("method", method.to_string()), ("status", status.to_string())];
+    counter!("webdav_requests_total", &labels).increment(1);
+    histogram!("webdav_request_duration_seconds", &labels).record(elapsed.as_secs_f64());
+}
+
+pub fn record_api_request(endpoint: &str) {
+    counter!("quark_api_requests_total", "endpoint" => endpoint.to_string()).increment(1);
+}
+
+pub fn record_api_error(endpoint: &str) {
+    counter!("quark_api_errors_total", "endpoint" => endpoint.to_string()).increment(1);
+}
+
+pub fn record_api_retry(endpoint: &str) {
+    counter!("quark_api_retries_total", "endpoint" => endpoint.to_string()).increment(1);
+}
+
+pub fn record_cache_lookup(hit: bool, entries: u64) {
+    if hit {
+        counter!("cache_hits_total").increment(1);
+    } else {
+        counter!("cache_misses_total").increment(1);
+    }
+    gauge!("cache_entries").set(entries as f64);
+}
+
+pub fn record_download(bytes: usize) {
+    counter!("download_bytes_total").increment(bytes as u64);
+}
+
+/// Counts an open connection until dropped
+pub struct ConnectionGuard;
+
+impl ConnectionGuard {
+    pub fn open() -> Self {
+        gauge!("webdav_active_connections").increment(1);
+        Self
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        gauge!("webdav_active_connections").decrement(1);
+    }
+}
+
+/// Run `f` on a new runtime with a local recorder, returns the recorded metrics
+#[cfg(test)]
+pub fn capture<F, Fut>(f: F) -> String
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let handle = recorder.handle();
+    metrics::with_local_recorder(&recorder, || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f())
+    });
+    handle.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::mock::MockQuark;
+
+    #[test]
+    fn test_unknown_methods() {
+        let metrics = capture(|| async {
+            record_request("PROPFIND", 207, Duration::from_millis(5));
+            record_request("BREW", 405, Duration::from_millis(5));
+            record_request("X-RANDOM-1", 405, Duration::from_millis(5));
+        });
+        assert!(metrics.contains(r#"webdav_requests_total{method="PROPFIND",status="207"} 1"#));
+        assert!(metrics.contains(r#"webdav_requests_total{method="other",status="405"} 2"#));
+        assert!(!metrics.contains("BREW"));
+    }
+
+    #[tokio::test]
+    async fn test_health_probes() {
+        let get = |url: String| async move { reqwest::Client::builder().no_proxy().build().unwrap().get(url).send().await.unwrap() };
+        let metrics = PrometheusBuilder::new().build_recorder().handle();
+        let mock = MockQuark::start().await;
+        let addr = serve("127.0.0.1:0".parse().unwrap(), Some(metrics), HealthCheck::new(vec![mock.drive()]))
+            .await
+            .unwrap();
+        assert_eq!(get(format!("http://{}/healthz", addr)).await.status(), StatusCode::OK);
+        assert_eq!(get(format!("http://{}/readyz", addr)).await.status(), StatusCode::OK);
+        assert_eq!(get(format!("http://{}/metrics", addr)).await.status(), StatusCode::OK);
+        assert_eq!(get(format!("http://{}/other", addr)).await.status(), StatusCode::NOT_FOUND);
+
+        let broken = MockQuark::start().await;
+        broken.fail("/1/clouddrive/member");
+        // the probes without metrics
+        let addr = serve("127.0.0.1:0".parse().unwrap(), None, HealthCheck::new(vec![mock.drive(), broken.drive()]))
+            .await
+            .unwrap();
+        assert_eq!(get(format!("http://{}/healthz", addr)).await.status(), StatusCode::OK);
+        assert_eq!(get(format!("http://{}/readyz", addr)).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(get(format!("http://{}/metrics", addr)).await.status(), StatusCode::NOT_FOUND);
+    }
+}
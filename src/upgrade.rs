@@ -0,0 +1,227 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use reqwest::header::{HeaderValue, ACCEPT};
+use self_update::backends::github;
+use self_update::update::{Release, ReleaseAsset};
+use self_update::{ArchiveKind, Compression, Download, Extract};
+use sha2::{Digest, Sha256};
+
+pub const GITHUB_API_URL: &str = "https://api.github.com";
+const REPO_OWNER: &str = "chenqimiao";
+const REPO_NAME: &str = "quarkdrive-webdav";
+const BIN_NAME: &str = "quarkdrive-webdav";
+
+/// The latest release if it is newer than the running binary, blocking
+pub fn check(api_url: &str) -> Result<Option<Release>> {
+    let release = github::Update::configure()
+        .repo_owner(REPO_OWNER)
+        .repo_name(REPO_NAME)
+        .with_url(api_url.trim_end_matches('/'))
+        .bin_name(BIN_NAME)
+        .current_version(env!("CARGO_PKG_VERSION"))
+        .build()?
+        .get_latest_release()
+        .context("failed to fetch the latest release")?;
+    if self_update::version::bump_is_greater(env!("CARGO_PKG_VERSION"), &release.version)? {
+        Ok(Some(release))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Download the release archive of this platform, verify it against its `.sha256` asset
+/// and replace the running binary, blocking
+pub fn upgrade(release: &Release) -> Result<()> {
+    let tmp_dir = self_update::TempDir::new()?;
+    let bin_path = download_binary(release, tmp_dir.path())?;
+    self_update::self_replace::self_replace(bin_path).context("failed to replace the binary")?;
+    Ok(())
+}
+
+/// Download and verify the release archive of this platform, returns the binary extracted into `dir`
+fn download_binary(release: &Release, dir: &Path) -> Result<PathBuf> {
+    let archive_name = format!("{}-{}.tar.gz", BIN_NAME, self_update::get_target());
+    let archive = find_asset(release, &archive_name)?;
+    let checksum = find_asset(release, &format!("{}.sha256", archive_name))?;
+
+    let mut checksum_file = Vec::new();
+    download(checksum, &mut checksum_file)?;
+    let mut data = Vec::new();
+    download(archive, &mut data)?;
+    verify_checksum(&data, &checksum_file).with_context(|| format!("invalid release asset {}", archive_name))?;
+
+    let archive_path = dir.join(&archive_name);
+    fs::write(&archive_path, &data)?;
+    Extract::from_source(&archive_path)
+        .archive(ArchiveKind::Tar(Some(Compression::Gz)))
+        .extract_file(dir, BIN_NAME)?;
+    Ok(dir.join(BIN_NAME))
+}
+
+fn find_asset<'a>(release: &'a Release, name: &str) -> Result<&'a ReleaseAsset> {
+    match release.assets.iter().find(|asset| asset.name == name) {
+        Some(asset) => Ok(asset),
+        None => bail!("release {} has no asset {}", release.version, name),
+    }
+}
+
+fn download(asset: &ReleaseAsset, dest: &mut Vec<u8>) -> Result<()> {
+    // asset urls point to the API, which only returns the file for this media type
+    Download::from_url(&asset.download_url)
+        .set_header(ACCEPT, HeaderValue::from_static("application/octet-stream"))
+        .download_to(dest)
+        .with_context(|| format!("failed to download {}", asset.name))?;
+    Ok(())
+}
+
+/// The checksum file is in `sha256sum` format, the first word is the hex digest
+fn verify_checksum(data: &[u8], checksum_file: &[u8]) -> Result<()> {
+    let content = String::from_utf8_lossy(checksum_file);
+    let Some(expected) = content.split_whitespace().next() else {
+        bail!("empty checksum file");
+    };
+    let actual = format!("{:x}", Sha256::digest(data));
+    if !expected.eq_ignore_ascii_case(&actual) {
+        bail!("checksum mismatch, expected {} but got {}", expected, actual);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::body::Incoming;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockState {
+        release: Value,
+        assets: HashMap<String, Vec<u8>>,
+    }
+
+    /// A GitHub releases API on a random local port, serving the latest release and its assets
+    struct MockReleases {
+        url: String,
+        state: Arc<Mutex<MockState>>,
+        // the blocking clients of self_update can't run on the runtime of the server
+        _runtime: tokio::runtime::Runtime,
+    }
+
+    impl MockReleases {
+        fn start() -> Self {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let state = Arc::new(Mutex::new(MockState::default()));
+            let server_state = state.clone();
+            runtime.spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = server_state.clone();
+                    tokio::spawn(async move {
+                        let service = service_fn(move |req: Request<Incoming>| {
+                            let state = state.lock().unwrap();
+                            let path = req.uri().path();
+                            let res = if path == format!("/repos/{}/{}/releases/latest", REPO_OWNER, REPO_NAME) {
+                                Response::new(Full::new(Bytes::from(state.release.to_string())))
+                            } else if let Some(data) = path.strip_prefix("/assets/").and_then(|name| state.assets.get(name)) {
+                                Response::new(Full::new(Bytes::from(data.clone())))
+                            } else {
+                                let mut res = Response::new(Full::new(Bytes::new()));
+                                *res.status_mut() = StatusCode::NOT_FOUND;
+                                res
+                            };
+                            async move { Ok::<_, Infallible>(res) }
+                        });
+                        let _ = hyper::server::conn::http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            });
+            Self {
+                url,
+                state,
+                _runtime: runtime,
+            }
+        }
+
+        /// Publish `version` as the latest release with the given assets
+        fn publish(&self, version: &str, assets: &[(&str, Vec<u8>)]) {
+            let mut state = self.state.lock().unwrap();
+            state.release = json!({
+                "tag_name": format!("v{}", version),
+                "created_at": "2025-01-01T00:00:00Z",
+                "assets": assets
+                    .iter()
+                    .map(|(name, _)| json!({ "name": name, "url": format!("{}/assets/{}", self.url, name) }))
+                    .collect::<Vec<_>>(),
+            });
+            state.assets = assets.iter().map(|(name, data)| (name.to_string(), data.clone())).collect();
+        }
+    }
+
+    fn archive(bin: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bin.len() as u64);
+        header.set_mode(0o755);
+        builder.append_data(&mut header, BIN_NAME, bin).unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mock = MockReleases::start();
+        mock.publish(env!("CARGO_PKG_VERSION"), &[]);
+        assert!(check(&mock.url).unwrap().is_none());
+
+        let archive_name = format!("{}-{}.tar.gz", BIN_NAME, self_update::get_target());
+        let data = archive(b"new binary");
+        let checksum = format!("{:x}  {}\n", Sha256::digest(&data), archive_name);
+        mock.publish("99.0.0", &[
+            (&archive_name, data.clone()),
+            (&format!("{}.sha256", archive_name), checksum.into_bytes()),
+        ]);
+        let release = check(&mock.url).unwrap().unwrap();
+        assert_eq!(release.version, "99.0.0");
+        let dir = tempfile::tempdir().unwrap();
+        let bin_path = download_binary(&release, dir.path()).unwrap();
+        assert_eq!(fs::read(bin_path).unwrap(), b"new binary");
+
+        // a tampered archive is refused
+        mock.publish("99.0.0", &[
+            (&archive_name, archive(b"evil binary")),
+            (&format!("{}.sha256", archive_name), format!("{:x}", Sha256::digest(&data)).into_bytes()),
+        ]);
+        let release = check(&mock.url).unwrap().unwrap();
+        let err = download_binary(&release, dir.path()).unwrap_err();
+        assert!(format!("{:#}", err).contains("checksum mismatch"), "{:#}", err);
+
+        // no build for this platform
+        mock.publish("99.0.0", &[("quarkdrive-webdav-other.tar.gz", data)]);
+        let release = check(&mock.url).unwrap().unwrap();
+        let err = download_binary(&release, dir.path()).unwrap_err();
+        assert!(err.to_string().contains(&archive_name), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let checksum = b"2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824  hello.tar.gz\n";
+        assert!(verify_checksum(b"hello", checksum).is_ok());
+        assert!(verify_checksum(b"hello!", checksum).is_err());
+        assert!(verify_checksum(b"hello", b"").is_err());
+    }
+}
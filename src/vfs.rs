@@ -1,34 +1,47 @@
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
 use std::io::{SeekFrom};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bytes::{Buf, Bytes, BytesMut};
 use dashmap::DashMap;
 use dav_server::{
     davpath::DavPath,
     fs::{
-        DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsStream, OpenOptions,
-        ReadDirMeta,
+        DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsResult, FsStream,
+        OpenOptions, ReadDirMeta,
     },
 };
-use futures_util::future::{ready, FutureExt};
-use tracing::{debug, error, trace};
+use futures_util::future::{join_all, ready, FutureExt};
+use md5::{Digest, Md5};
+use moka::future::Cache as MokaCache;
+use serde::Serialize;
+use sha1::Sha1;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, error, info, trace, warn};
 use crate::{
     cache::Cache,
-    drive::{QuarkDrive, QuarkFile},
+    drive::{error::QuarkError, model::UploadPreData, QuarkDrive, QuarkFile},
+    stats,
 };
 
+const QUOTA_CACHE_TTL: u64 = 60;
+
 #[derive(Clone)]
 pub struct QuarkDriveFileSystem {
     drive: QuarkDrive,
     pub(crate) dir_cache: Cache,
+    quota_cache: MokaCache<(), (u64, u64)>,
     uploading: Arc<DashMap<String, Vec<QuarkFile>>>,
     root: PathBuf,
     no_trash: bool,
     read_only: bool,
+    /// Global read-only mode, shared by all file systems and switched at runtime
+    read_only_switch: Arc<AtomicBool>,
     upload_buffer_size: usize,
     skip_upload_same_size: bool,
     prefer_http_download: bool,
@@ -39,29 +52,45 @@ impl QuarkDriveFileSystem {
     pub fn new(drive: QuarkDrive, root: String, cache_size: u64, cache_ttl: u64) -> Result<Self> {
         let dir_cache = Cache::new(cache_size, cache_ttl, drive.clone());
         debug!("dir cache initialized");
-        let root = if root.starts_with('/') {
-            PathBuf::from(root)
-        } else {
-            Path::new("/").join(root)
-        };
+        let root = normalize_root(&root);
+        let quota_cache = MokaCache::builder()
+            .max_capacity(1)
+            .time_to_live(Duration::from_secs(QUOTA_CACHE_TTL))
+            .build();
         Ok(Self {
             drive,
             dir_cache,
+            quota_cache,
             uploading: Arc::new(DashMap::new()),
             root,
             no_trash: false,
             read_only: false,
+            read_only_switch: Arc::new(AtomicBool::new(false)),
             upload_buffer_size: 16 * 1024 * 1024,
             skip_upload_same_size: false,
             prefer_http_download: false,
         })
     }
 
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn set_root(&mut self, root: &str) -> &mut Self {
+        self.root = normalize_root(root);
+        self
+    }
+
     pub fn set_read_only(&mut self, read_only: bool) -> &mut Self {
         self.read_only = read_only;
         self
     }
 
+    pub fn set_read_only_switch(&mut self, read_only_switch: Arc<AtomicBool>) -> &mut Self {
+        self.read_only_switch = read_only_switch;
+        self
+    }
+
     pub fn set_no_trash(&mut self, no_trash: bool) -> &mut Self {
         self.no_trash = no_trash;
         self
@@ -82,6 +111,48 @@ impl QuarkDriveFileSystem {
         self
     }
 
+    pub fn drive(&self) -> &QuarkDrive {
+        &self.drive
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only || self.read_only_switch.load(Ordering::Relaxed)
+    }
+
+    /// Invalidate the cached listing of a directory relative to the root, or everything below it
+    pub async fn invalidate(&self, path: &str, recursive: bool) {
+        let path = path.trim_matches('/');
+        let path = if path.is_empty() {
+            self.root.clone()
+        } else {
+            self.root.join(path)
+        };
+        if recursive {
+            self.dir_cache.invalidate_subtree(&path).await;
+        } else {
+            self.dir_cache.invalidate(&path).await;
+        }
+    }
+
+    /// Files being uploaded, with their full path in the drive
+    pub fn uploads(&self) -> Vec<UploadInfo> {
+        self.uploading
+            .iter()
+            .flat_map(|entry| {
+                let parent = PathBuf::from(entry.key());
+                entry
+                    .value()
+                    .iter()
+                    .map(|file| UploadInfo {
+                        account: None,
+                        path: parent.join(&file.file_name).to_string_lossy().into_owned(),
+                        size: file.size,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
     async fn find_in_cache(&self, path: &Path) -> Result<Option<QuarkFile>, FsError> {
         if let Some(parent) = path.parent() {
             let parent_str = parent.to_string_lossy();
@@ -90,7 +161,11 @@ impl QuarkDriveFileSystem {
                 .ok_or(FsError::NotFound)?
                 .to_string_lossy()
                 .into_owned();
-            let file = self.dir_cache.get_or_insert(&parent_str).await.and_then(|files| {
+            let files = self.dir_cache.get_or_insert(&parent_str).await.map_err(|err| {
+                error!(path = %parent.display(), error = %err, "list directory failed");
+                fs_error(&err)
+            })?;
+            let file = files.and_then(|files| {
                 for file in &files {
                     if file.file_name == file_name {
                         return Some(file.clone());
@@ -111,11 +186,134 @@ impl QuarkDriveFileSystem {
             trace!(path = %path.display(), file_id = %file.fid, "file found in cache");
             Ok(Some(file))
         } else {
-            // find in drive
-            Ok(None)
+            // find in uploading files
+            let file = path.parent().and_then(|parent| {
+                let file_name = path.file_name()?.to_string_lossy();
+                self.uploading_files(parent)
+                    .into_iter()
+                    .find(|file| file.file_name == file_name)
+            });
+            if file.is_some() {
+                trace!(path = %path.display(), "file found in uploading files");
+            }
+            Ok(file)
+        }
+    }
+
+    fn uploading_files(&self, parent: &Path) -> Vec<QuarkFile> {
+        let key = parent.to_string_lossy();
+        self.uploading
+            .get(key.as_ref())
+            .map(|files| files.clone())
+            .unwrap_or_default()
+    }
+
+    fn add_uploading_file(&self, parent: &Path, file: QuarkFile) {
+        let key = parent.to_string_lossy().into_owned();
+        trace!(parent = %key, file_name = %file.file_name, "add uploading file");
+        self.uploading.entry(key).or_default().push(file);
+    }
+
+    fn remove_uploading_file(&self, parent: &Path, file_name: &str) {
+        let key = parent.to_string_lossy().into_owned();
+        trace!(parent = %key, file_name = %file_name, "remove uploading file");
+        if let Some(mut files) = self.uploading.get_mut(&key)
+            && let Some(index) = files.iter().position(|f| f.file_name == file_name)
+        {
+            files.remove(index);
+        }
+        self.uploading.remove_if(&key, |_, files| files.is_empty());
+    }
+
+
+    /// Move `file` to `to_parent` as `new_name`. An `existing` file of that name is only removed
+    /// once the source is in place, until then the source has a temporary name to avoid a conflict.
+    async fn move_file(
+        &self,
+        file: &QuarkFile,
+        from_parent: &Path,
+        to_parent: &Path,
+        new_name: &str,
+        existing: Option<QuarkFile>,
+    ) -> Result<(), FsError> {
+        let mut name = file.file_name.clone();
+        if existing.is_some() {
+            name = format!(".{}.{}.move", new_name, file.fid);
+            self.drive.rename_file(&file.fid, &name).await.map_err(|err| {
+                error!(file_name = %file.file_name, error = %err, "rename file failed");
+                fs_error(&err)
+            })?;
+        }
+        if from_parent != to_parent {
+            let moved = match self.get_file(to_parent.to_path_buf()).await {
+                Ok(Some(to_parent_file)) => self
+                    .drive
+                    .move_files(vec![file.fid.clone()], &to_parent_file.fid)
+                    .await
+                    .map_err(|err| {
+                        error!(file_name = %file.file_name, to = %to_parent.display(), error = %err, "move file failed");
+                        fs_error(&err)
+                    }),
+                Ok(None) => Err(FsError::NotFound),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = moved {
+                if name != file.file_name
+                    && let Err(err) = self.drive.rename_file(&file.fid, &file.file_name).await
+                {
+                    error!(file_name = %file.file_name, name = %name, error = %err, "restore file name failed");
+                }
+                return Err(err);
+            }
+        }
+        if let Some(existing) = existing {
+            self.drive
+                .remove_file(&existing.fid, !self.no_trash)
+                .await
+                .map_err(|err| {
+                    error!(file_name = %existing.file_name, moved_as = %name, error = %err, "remove overwritten file failed");
+                    fs_error(&err)
+                })?;
+        }
+        if name != new_name {
+            self.drive.rename_file(&file.fid, new_name).await.map_err(|err| {
+                error!(file_name = %name, new_name = %new_name, error = %err, "rename file failed");
+                fs_error(&err)
+            })?;
         }
+        Ok(())
     }
 
+    /// Give a copy its name at `to`, where the copy keeps the source name or gets an
+    /// auto-generated one if that is taken. The `existing` file is removed first.
+    async fn replace_with_copy(
+        &self,
+        copy_fid: &str,
+        to: &Path,
+        existing: Option<QuarkFile>,
+        renamed: bool,
+    ) -> Result<(), FsError> {
+        if let Some(existing) = &existing
+            && let Err(err) = self.drive.remove_file(&existing.fid, !self.no_trash).await
+        {
+            error!(path = %to.display(), error = %err, "remove overwritten file failed");
+            if let Err(err) = self.drive.remove_file(copy_fid, true).await {
+                warn!(path = %to.display(), copy_fid = %copy_fid, error = %err, "remove copy failed");
+            }
+            return Err(fs_error(&err));
+        }
+        if renamed || existing.is_some() {
+            let new_name = to
+                .file_name()
+                .ok_or(FsError::Forbidden)?
+                .to_string_lossy();
+            self.drive.rename_file(copy_fid, &new_name).await.map_err(|err| {
+                error!(path = %to.display(), copy_fid = %copy_fid, error = %err, "rename copied file failed");
+                fs_error(&err)
+            })?;
+        }
+        Ok(())
+    }
 
     fn normalize_dav_path(&self, dav_path: &DavPath) -> PathBuf {
         let path = dav_path.as_pathbuf();
@@ -150,41 +348,247 @@ impl DavFileSystem for QuarkDriveFileSystem {
                 .get_file(parent_path.to_path_buf())
                 .await?
                 .ok_or(FsError::NotFound)?;
-            let sha1 = options.checksum.and_then(|c| {
-                if let Some((algo, hash)) = c.split_once(':') {
-                    if algo.eq_ignore_ascii_case("sha1") {
-                        Some(hash.to_string())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            });
-            let mut dav_file = if let Some(file) = self.get_file(path.clone()).await? {
+            let (md5, sha1) = options
+                .checksum
+                .as_deref()
+                .map(parse_checksums)
+                .unwrap_or_default();
+            let mut replace_fid = None;
+            let mut skip_upload = false;
+            let file = if let Some(file) = self.get_file(path.clone()).await? {
                 if options.write && options.create_new {
                     return Err(FsError::Exists);
                 }
-                if options.write && self.read_only {
+                if options.write && self.is_read_only() {
                     return Err(FsError::Forbidden);
                 }
-                QuarkDavFile::new(
-                    self.clone(),
-                    file,
-                    parent_file.fid,
-                    parent_path.to_path_buf(),
+                if options.write && self.skip_upload_same_size && options.size == Some(file.size) {
+                    info!(path = %path.display(), size = file.size, "skip uploading same size file");
+                    skip_upload = true;
+                    file
+                } else if options.write {
+                    // overwrite the existing file with a new upload
+                    if !file.fid.is_empty() {
+                        replace_fid = Some(file.fid);
+                    }
+                    QuarkFile::new_uploading(
+                        file.file_name,
+                        parent_file.fid.clone(),
+                        options.size.unwrap_or_default(),
+                    )
+                } else {
+                    file
+                }
+            } else if options.write && (options.create || options.create_new) {
+                if self.is_read_only() {
+                    return Err(FsError::Forbidden);
+                }
+                let name = path
+                    .file_name()
+                    .ok_or(FsError::NotFound)?
+                    .to_string_lossy()
+                    .into_owned();
+                QuarkFile::new_uploading(
+                    name,
+                    parent_file.fid.clone(),
                     options.size.unwrap_or_default(),
-                    sha1,
                 )
             } else {
                 return Err(FsError::NotFound);
             };
+            let mut dav_file = QuarkDavFile::new(
+                self.clone(),
+                file,
+                parent_file.fid,
+                parent_path.to_path_buf(),
+                options.size,
+                md5,
+                sha1,
+            );
+            dav_file.write = options.write;
+            dav_file.upload_state.replace_fid = replace_fid;
+            dav_file.upload_state.skip = skip_upload;
+            if options.write && !skip_upload {
+                self.add_uploading_file(parent_path, dav_file.file.clone());
+                dav_file.upload_state.placeholder = true;
+            }
             dav_file.http_download = self.prefer_http_download;
             Ok(Box::new(dav_file) as Box<dyn DavFile>)
         }
             .boxed()
     }
 
+    fn create_dir<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<'a, ()> {
+        let path = self.normalize_dav_path(dav_path);
+        debug!(path = %path.display(), "fs: create_dir");
+        async move {
+            if self.is_read_only() {
+                return Err(FsError::Forbidden);
+            }
+            let parent_path = path.parent().ok_or(FsError::Forbidden)?;
+            let parent_file = self
+                .get_file(parent_path.to_path_buf())
+                .await?
+                .ok_or(FsError::NotFound)?;
+            if !parent_file.dir {
+                return Err(FsError::Forbidden);
+            }
+            let name = path
+                .file_name()
+                .ok_or(FsError::Forbidden)?
+                .to_string_lossy()
+                .into_owned();
+            self.drive
+                .create_folder(&parent_file.fid, &name)
+                .await
+                .map_err(|err| {
+                    error!(path = %path.display(), error = %err, "create folder failed");
+                    fs_error(&err)
+                })?;
+            self.dir_cache.invalidate(parent_path).await;
+            Ok(())
+        }
+            .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<'a, ()> {
+        let path = self.normalize_dav_path(dav_path);
+        debug!(path = %path.display(), "fs: remove_dir");
+        async move {
+            if self.is_read_only() {
+                return Err(FsError::Forbidden);
+            }
+            let file = self
+                .get_file(path.clone())
+                .await?
+                .ok_or(FsError::NotFound)?;
+            if !file.dir {
+                return Err(FsError::Forbidden);
+            }
+            self.drive
+                .remove_file(&file.fid, !self.no_trash)
+                .await
+                .map_err(|err| {
+                    error!(path = %path.display(), error = %err, "remove directory failed");
+                    fs_error(&err)
+                })?;
+            self.dir_cache.invalidate_subtree(&path).await;
+            self.dir_cache.invalidate_parent(&path).await;
+            Ok(())
+        }
+            .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<'a, ()> {
+        let path = self.normalize_dav_path(dav_path);
+        debug!(path = %path.display(), "fs: remove_file");
+        async move {
+            if self.is_read_only() {
+                return Err(FsError::Forbidden);
+            }
+            let file = self
+                .get_file(path.clone())
+                .await?
+                .ok_or(FsError::NotFound)?;
+            self.drive
+                .remove_file(&file.fid, !self.no_trash)
+                .await
+                .map_err(|err| {
+                    error!(path = %path.display(), error = %err, "remove file failed");
+                    fs_error(&err)
+                })?;
+            self.dir_cache.invalidate_parent(&path).await;
+            Ok(())
+        }
+            .boxed()
+    }
+
+    fn rename<'a>(&'a self, from_dav: &'a DavPath, to_dav: &'a DavPath) -> FsFuture<'a, ()> {
+        let from = self.normalize_dav_path(from_dav);
+        let to = self.normalize_dav_path(to_dav);
+        debug!(from = %from.display(), to = %to.display(), "fs: rename");
+        async move {
+            if self.is_read_only() {
+                return Err(FsError::Forbidden);
+            }
+            let file = self
+                .get_file(from.clone())
+                .await?
+                .ok_or(FsError::NotFound)?;
+            let from_parent = from.parent().ok_or(FsError::Forbidden)?;
+            let to_parent = to.parent().ok_or(FsError::Forbidden)?;
+            let new_name = to
+                .file_name()
+                .ok_or(FsError::Forbidden)?
+                .to_string_lossy()
+                .into_owned();
+
+            // collections are removed by the handler already, only an existing file is left to overwrite
+            let existing = match self.get_file(to.clone()).await? {
+                Some(existing) if existing.dir => return Err(FsError::Exists),
+                existing => existing,
+            };
+            let result = self
+                .move_file(&file, from_parent, to_parent, &new_name, existing)
+                .await;
+
+            if file.dir {
+                self.dir_cache.invalidate_subtree(&from).await;
+            }
+            self.dir_cache.invalidate(from_parent).await;
+            self.dir_cache.invalidate(to_parent).await;
+            result
+        }
+            .boxed()
+    }
+
+    fn copy<'a>(&'a self, from_dav: &'a DavPath, to_dav: &'a DavPath) -> FsFuture<'a, ()> {
+        let from = self.normalize_dav_path(from_dav);
+        let to = self.normalize_dav_path(to_dav);
+        debug!(from = %from.display(), to = %to.display(), "fs: copy");
+        async move {
+            if self.is_read_only() {
+                return Err(FsError::Forbidden);
+            }
+            let file = self
+                .get_file(from.clone())
+                .await?
+                .ok_or(FsError::NotFound)?;
+            let from_parent = from.parent().ok_or(FsError::Forbidden)?;
+            let to_parent = to.parent().ok_or(FsError::Forbidden)?;
+            let new_name = to
+                .file_name()
+                .ok_or(FsError::Forbidden)?
+                .to_string_lossy()
+                .into_owned();
+            let to_parent_file = self
+                .get_file(to_parent.to_path_buf())
+                .await?
+                .ok_or(FsError::NotFound)?;
+
+            let existing = match self.get_file(to.clone()).await? {
+                Some(existing) if existing.dir => return Err(FsError::Exists),
+                existing => existing,
+            };
+
+            let copies = self
+                .drive
+                .copy_files(vec![file.fid.clone()], &to_parent_file.fid)
+                .await
+                .map_err(|err| {
+                    error!(from = %from.display(), to = %to.display(), error = %err, "copy file failed");
+                    fs_error(&err)
+                })?;
+            let copy_fid = copies.first().ok_or(FsError::GeneralFailure)?;
+            let result = self
+                .replace_with_copy(copy_fid, &to, existing, from_parent == to_parent || file.file_name != new_name)
+                .await;
+            self.dir_cache.invalidate(to_parent).await;
+            result
+        }
+            .boxed()
+    }
+
     fn read_dir<'a>(
         &'a self,
         path: &'a DavPath,
@@ -193,16 +597,18 @@ impl DavFileSystem for QuarkDriveFileSystem {
         let path = self.normalize_dav_path(path);
         debug!(path = %path.display(), "fs: read_dir");
         async move {
-            let files = self.dir_cache.get_or_insert(&path.to_string_lossy())
+            let mut files = self.dir_cache.get_or_insert(&path.to_string_lossy())
                 .await
-                .ok_or(FsError::NotFound)
-                .and_then(|files| {
-                    if files.is_empty() {
-                        Err(FsError::NotFound)
-                    } else {
-                        Ok(files)
-                    }
-                })?;
+                .map_err(|err| {
+                    error!(path = %path.display(), error = %err, "list directory failed");
+                    fs_error(&err)
+                })?
+                .ok_or(FsError::NotFound)?;
+            for file in self.uploading_files(&path) {
+                if !files.iter().any(|f| f.file_name == file.file_name) {
+                    files.push(file);
+                }
+            }
 
             // 创建包含结果的向量
             let mut v: Vec<Result<Box<dyn DavDirEntry>, FsError>> = Vec::with_capacity(files.len());
@@ -235,7 +641,7 @@ impl DavFileSystem for QuarkDriveFileSystem {
         Box::pin(ready(true))
     }
 
-    fn get_prop(&self, dav_path: &DavPath, prop: dav_server::fs::DavProp) -> FsFuture<Vec<u8>> {
+    fn get_prop(&self, dav_path: &DavPath, prop: dav_server::fs::DavProp) -> FsFuture<'_, Vec<u8>> {
         let path = self.normalize_dav_path(dav_path);
         let prop_name = match prop.prefix.as_ref() {
             Some(prefix) => format!("{}:{}", prefix, prop.name),
@@ -248,36 +654,71 @@ impl DavFileSystem for QuarkDriveFileSystem {
             .boxed()
     }
 
-    fn get_quota(&self) -> FsFuture<(u64, Option<u64>)> {
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
         debug!("fs: get_quota");
         async move {
-            Err(FsError::NotImplemented)
+            let (used, total) = self
+                .quota_cache
+                .try_get_with((), self.drive.get_quota())
+                .await
+                .map_err(|err| {
+                    error!(error = %err, "get quota failed");
+                    fs_error(&err)
+                })?;
+            Ok((used, Some(total)))
         }
             .boxed()
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct UploadState {
-    size: u64,
+    /// Declared by the client, `None` for chunked requests without a length
+    size: Option<u64>,
     buffer: BytesMut,
+    /// Full chunks of content that can't be uploaded before it is complete
+    spool: Option<tokio::fs::File>,
+    spooled_chunks: u64,
     chunk_count: u64,
     chunk: u64,
     upload_id: String,
-    upload_urls: Vec<String>,
+    md5: Option<String>,
     sha1: Option<String>,
+    pre: Option<UploadPreData>,
+    /// The file being overwritten, replaced only after the new content is committed
+    replace_fid: Option<String>,
+    etags: Vec<String>,
+    md5_hasher: Md5,
+    sha1_hasher: Sha1,
+    hash_updated: bool,
+    instant: bool,
+    skip: bool,
+    placeholder: bool,
+    committed: bool,
 }
 
 impl Default for UploadState {
     fn default() -> Self {
         Self {
-            size: 0,
+            size: None,
             buffer: BytesMut::new(),
+            spool: None,
+            spooled_chunks: 0,
             chunk_count: 0,
             chunk: 1,
             upload_id: String::new(),
-            upload_urls: Vec::new(),
+            md5: None,
             sha1: None,
+            pre: None,
+            replace_fid: None,
+            etags: Vec::new(),
+            md5_hasher: Md5::new(),
+            sha1_hasher: Sha1::new(),
+            hash_updated: false,
+            instant: false,
+            skip: false,
+            placeholder: false,
+            committed: false,
         }
     }
 }
@@ -288,6 +729,8 @@ struct QuarkDavFile {
     parent_file_id: String,
     parent_dir: PathBuf,
     current_pos: u64,
+    /// Opened for writing, the content is uploaded in order so the position only moves by writing
+    write: bool,
     upload_state: UploadState,
     http_download: bool,
     
@@ -311,7 +754,8 @@ impl QuarkDavFile {
         file: QuarkFile,
         parent_file_id: String,
         parent_dir: PathBuf,
-        size: u64,
+        size: Option<u64>,
+        md5: Option<String>,
         sha1: Option<String>,
     ) -> Self {
         Self {
@@ -320,8 +764,10 @@ impl QuarkDavFile {
             parent_file_id,
             parent_dir,
             current_pos: 0,
+            write: false,
             upload_state: UploadState {
                 size,
+                md5,
                 sha1,
                 ..Default::default()
             },
@@ -332,10 +778,336 @@ impl QuarkDavFile {
     async fn get_download_url(&self) -> Result<String, FsError> {
         self.fs.drive.get_download_url(&self.file.fid).await.map_err(|err| {
             error!(file_id = %self.file.fid, file_name = %self.file.file_name, error = %err, "get download url failed");
-            FsError::GeneralFailure
+            fs_error(&err)
         })
     }
 
+    fn mime_type(&self) -> String {
+        mime_guess::from_path(&self.file.file_name)
+            .first_or_octet_stream()
+            .to_string()
+    }
+
+    /// An overwrite is uploaded next to the old file under a temporary name
+    fn upload_name(&self) -> String {
+        match &self.upload_state.replace_fid {
+            Some(fid) => format!(".{}.{}.upload", self.file.file_name, fid),
+            None => self.file.file_name.clone(),
+        }
+    }
+
+    fn computed_hashes(&self) -> (String, String) {
+        let md5 = format!("{:x}", self.upload_state.md5_hasher.clone().finalize());
+        let sha1 = format!("{:x}", self.upload_state.sha1_hasher.clone().finalize());
+        (md5, sha1)
+    }
+
+    /// Pre-upload the file. When `complete` is set the whole content has been written.
+    ///
+    /// The hashes of a complete content are checked against the drive first,
+    /// so that existing content can be stored without transferring any data.
+    async fn prepare_for_upload(&mut self, complete: bool) -> Result<(), FsError> {
+        if self.upload_state.pre.is_some() {
+            return Ok(());
+        }
+        let size = self.upload_state.size.unwrap_or(self.current_pos);
+        let pre = self
+            .fs
+            .drive
+            .pre_upload(&self.parent_file_id, &self.upload_name(), size, &self.mime_type())
+            .await
+            .map_err(|err| {
+                error!(file_name = %self.file.file_name, error = %err, "pre upload failed");
+                fs_error(&err)
+            })?;
+        let chunk_size = self.fs.upload_buffer_size as u64;
+        self.upload_state.chunk_count = size.div_ceil(chunk_size).max(1);
+        self.upload_state.upload_id = pre.upload_id.clone();
+        self.file.fid = pre.fid.clone();
+        let task_id = pre.task_id.clone();
+        self.upload_state.instant = pre.finish;
+        self.upload_state.pre = Some(pre);
+
+        if complete && !self.upload_state.instant {
+            let (md5, sha1) = self.computed_hashes();
+            let finish = self
+                .fs
+                .drive
+                .update_hash(&task_id, &md5, &sha1)
+                .await
+                .map_err(|err| {
+                    error!(file_name = %self.file.file_name, error = %err, "update hash failed");
+                    fs_error(&err)
+                })?;
+            self.upload_state.hash_updated = true;
+            self.upload_state.instant = finish;
+        }
+        if self.upload_state.instant {
+            info!(file_name = %self.file.file_name, size = size, "instant upload by hash");
+        }
+        Ok(())
+    }
+
+    async fn upload_chunk(&mut self, chunk: Bytes) -> Result<(), FsError> {
+        self.prepare_for_upload(false).await?;
+        if self.upload_state.instant {
+            return Ok(());
+        }
+        let pre = self.upload_state.pre.as_ref().ok_or(FsError::GeneralFailure)?;
+        debug!(
+            file_name = %self.file.file_name,
+            upload_id = %self.upload_state.upload_id,
+            chunk = self.upload_state.chunk,
+            chunk_count = self.upload_state.chunk_count,
+            size = chunk.len(),
+            "upload chunk",
+        );
+        let etag = self
+            .fs
+            .drive
+            .upload_part(pre, &self.mime_type(), self.upload_state.chunk, chunk)
+            .await
+            .map_err(|err| {
+                error!(
+                    file_name = %self.file.file_name,
+                    chunk = self.upload_state.chunk,
+                    error = %err,
+                    "upload chunk failed"
+                );
+                fs_error(&err)
+            })?;
+        self.upload_state.etags.push(etag);
+        self.upload_state.chunk += 1;
+        Ok(())
+    }
+
+    async fn complete_upload(&mut self) -> Result<(), FsError> {
+        let pre = self.upload_state.pre.clone().ok_or(FsError::GeneralFailure)?;
+        let (md5, sha1) = self.computed_hashes();
+        if !self.upload_state.hash_updated {
+            self.fs
+                .drive
+                .update_hash(&pre.task_id, &md5, &sha1)
+                .await
+                .map_err(|err| {
+                    error!(file_name = %self.file.file_name, error = %err, "update hash failed");
+                    fs_error(&err)
+                })?;
+        }
+        self.fs
+            .drive
+            .commit_upload(&pre, &self.upload_state.etags)
+            .await
+            .map_err(|err| {
+                error!(file_name = %self.file.file_name, error = %err, "commit upload failed");
+                fs_error(&err)
+            })?;
+        self.fs.drive.finish_upload(&pre).await.map_err(|err| {
+            error!(file_name = %self.file.file_name, error = %err, "finish upload failed");
+            fs_error(&err)
+        })?;
+        Ok(())
+    }
+
+    /// Check each hash sent by the client against the content
+    fn verify_checksums(&self) -> Result<(), FsError> {
+        let (md5, sha1) = self.computed_hashes();
+        let checksums = [("md5", &self.upload_state.md5, md5), ("sha1", &self.upload_state.sha1, sha1)];
+        for (algorithm, expected, actual) in checksums {
+            if let Some(expected) = expected
+                && !expected.eq_ignore_ascii_case(&actual)
+            {
+                error!(
+                    file_name = %self.file.file_name,
+                    algorithm = algorithm,
+                    expected = %expected,
+                    actual = %actual,
+                    "checksum mismatch"
+                );
+                return Err(FsError::GeneralFailure);
+            }
+        }
+        Ok(())
+    }
+
+    /// Content of unknown size, or with client hashes that have to be verified first,
+    /// is only uploaded once complete
+    fn is_spooled(&self) -> bool {
+        self.upload_state.size.is_none() || self.upload_state.md5.is_some() || self.upload_state.sha1.is_some()
+    }
+
+    /// Upload the chunks written to the spool file so far
+    async fn upload_spooled(&mut self, mut spool: tokio::fs::File) -> Result<(), FsError> {
+        spool.seek(SeekFrom::Start(0)).await.map_err(|err| {
+            error!(file_name = %self.file.file_name, error = %err, "rewind spool file failed");
+            FsError::GeneralFailure
+        })?;
+        for _ in 0..self.upload_state.spooled_chunks {
+            let mut chunk = vec![0; self.fs.upload_buffer_size];
+            spool.read_exact(&mut chunk).await.map_err(|err| {
+                error!(file_name = %self.file.file_name, error = %err, "read spool file failed");
+                FsError::GeneralFailure
+            })?;
+            self.upload_chunk(Bytes::from(chunk)).await?;
+        }
+        Ok(())
+    }
+
+    async fn commit_upload(&mut self) -> Result<(), FsError> {
+        if let Some(size) = self.upload_state.size
+            && self.current_pos != size
+        {
+            error!(file_name = %self.file.file_name, expected = size, actual = self.current_pos, "upload size mismatch");
+            return Err(FsError::GeneralFailure);
+        }
+        self.verify_checksums()?;
+        // the whole content is buffered or spooled, pre-upload with its hashes
+        self.prepare_for_upload(true).await?;
+        if !self.upload_state.instant {
+            if let Some(spool) = self.upload_state.spool.take() {
+                self.upload_spooled(spool).await?;
+            }
+            // the last part, always upload one even if the file is empty
+            if !self.upload_state.buffer.is_empty() || self.upload_state.etags.is_empty() {
+                let chunk = self.upload_state.buffer.split().freeze();
+                self.upload_chunk(chunk).await?;
+            }
+            self.complete_upload().await?;
+        }
+        self.upload_state.committed = true;
+        self.replace_existing().await?;
+        self.file.size = self.current_pos;
+        self.file.format_type = self.mime_type();
+        info!(
+            file_id = %self.file.fid,
+            file_name = %self.file.file_name,
+            size = self.file.size,
+            "upload completed"
+        );
+        self.fs
+            .dir_cache
+            .insert_file(&self.parent_dir, self.file.clone())
+            .await;
+        Ok(())
+    }
+
+    /// Remove the overwritten file and give the committed upload its name
+    async fn replace_existing(&mut self) -> Result<(), FsError> {
+        let Some(fid) = self.upload_state.replace_fid.clone() else {
+            return Ok(());
+        };
+        self.fs
+            .drive
+            .remove_file(&fid, !self.fs.no_trash)
+            .await
+            .map_err(|err| {
+                error!(file_name = %self.file.file_name, error = %err, "remove overwritten file failed");
+                fs_error(&err)
+            })?;
+        self.fs
+            .drive
+            .rename_file(&self.file.fid, &self.file.file_name)
+            .await
+            .map_err(|err| {
+                error!(file_name = %self.file.file_name, upload_name = %self.upload_name(), error = %err, "rename uploaded file failed");
+                fs_error(&err)
+            })?;
+        self.upload_state.replace_fid = None;
+        Ok(())
+    }
+
+    /// Discard the uploaded parts of an upload that won't be committed
+    fn abort_upload(&mut self) {
+        if self.upload_state.committed || self.upload_state.instant {
+            return;
+        }
+        let Some(pre) = self.upload_state.pre.take() else {
+            return;
+        };
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let drive = self.fs.drive.clone();
+        let file_name = self.file.file_name.clone();
+        handle.spawn(async move {
+            if let Err(err) = drive.abort_upload(&pre).await {
+                warn!(file_name = %file_name, error = %err, "abort upload failed");
+            }
+        });
+    }
+
+    fn remove_placeholder(&mut self) {
+        if self.upload_state.placeholder {
+            self.fs
+                .remove_uploading_file(&self.parent_dir, &self.file.file_name);
+            self.upload_state.placeholder = false;
+        }
+    }
+
+    async fn write_chunk(&mut self, buf: Bytes) -> Result<(), FsError> {
+        if self.upload_state.committed {
+            error!(file_name = %self.file.file_name, "write after upload completed");
+            return Err(FsError::GeneralFailure);
+        }
+        if let Some(size) = self.upload_state.size
+            && self.current_pos + buf.len() as u64 > size
+        {
+            error!(file_name = %self.file.file_name, size = size, "write past the declared size");
+            return Err(FsError::GeneralFailure);
+        }
+        if self.upload_state.skip {
+            // same size file exists, accept and discard the content
+            self.current_pos += buf.len() as u64;
+            return Ok(());
+        }
+        self.upload_state.md5_hasher.update(&buf);
+        self.upload_state.sha1_hasher.update(&buf);
+        self.current_pos += buf.len() as u64;
+        if self.upload_state.instant {
+            // content already exists in the drive, discard the rest
+            return Ok(());
+        }
+        self.upload_state.buffer.extend_from_slice(&buf);
+        let chunk_size = self.fs.upload_buffer_size;
+        while self.upload_state.buffer.len() >= chunk_size {
+            let chunk = self.upload_state.buffer.split_to(chunk_size).freeze();
+            if self.is_spooled() {
+                self.spool_chunk(chunk).await?;
+            } else {
+                self.upload_chunk(chunk).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep a chunk on disk until the content is complete
+    async fn spool_chunk(&mut self, chunk: Bytes) -> Result<(), FsError> {
+        if self.upload_state.spool.is_none() {
+            let file = tokio::task::spawn_blocking(tempfile::tempfile)
+                .await
+                .map_err(|_| FsError::GeneralFailure)?
+                .map_err(|err| {
+                    error!(file_name = %self.file.file_name, error = %err, "create spool file failed");
+                    FsError::GeneralFailure
+                })?;
+            self.upload_state.spool = Some(tokio::fs::File::from_std(file));
+        }
+        let spool = self.upload_state.spool.as_mut().ok_or(FsError::GeneralFailure)?;
+        spool.write_all(&chunk).await.map_err(|err| {
+            error!(file_name = %self.file.file_name, error = %err, "write spool file failed");
+            FsError::GeneralFailure
+        })?;
+        self.upload_state.spooled_chunks += 1;
+        Ok(())
+    }
+}
+
+impl Drop for QuarkDavFile {
+    fn drop(&mut self) {
+        // upload aborted before flush
+        self.abort_upload();
+        self.remove_placeholder();
+    }
 }
 
 impl DavFile for QuarkDavFile {
@@ -348,23 +1120,22 @@ impl DavFile for QuarkDavFile {
             .boxed()
     }
 
-    fn redirect_url(&mut self) -> FsFuture<Option<String>> {
+    fn redirect_url(&mut self) -> FsFuture<'_, Option<String>> {
         debug!(file_id = %self.file.fid, file_name = %self.file.file_name, "file: redirect_url");
         async move {
             if self.file.fid.is_empty() {
                 return Err(FsError::NotFound);
             }
-            let download_url = self.fs.drive.get_download_url(&self.file.fid).await.unwrap();
+            let download_url = self.get_download_url().await?;
 
-            return Ok(Some(download_url));
-            
+            Ok(Some(download_url))
         }
             .boxed()
     }
     
 
 
-    fn seek(&mut self, pos: SeekFrom) -> FsFuture<u64> {
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
         debug!(
             file_id = %self.file.fid,
             file_name = %self.file.file_name,
@@ -377,21 +1148,32 @@ impl DavFile for QuarkDavFile {
                 SeekFrom::End(pos) => (self.file.size as i64 + pos) as u64,
                 SeekFrom::Current(size) => self.current_pos + size as u64,
             };
+            if self.write && new_pos != self.current_pos {
+                // the upload size and hashes follow the written bytes, there are no holes to seek to
+                error!(file_name = %self.file.file_name, pos = new_pos, "seek on an upload");
+                return Err(FsError::NotImplemented);
+            }
             self.current_pos = new_pos;
             Ok(new_pos)
         }
             .boxed()
     }
 
-    fn write_buf(&mut self, buf: Box<dyn Buf + Send>) -> FsFuture<()> {
-        todo!()
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        debug!(file_name = %self.file.file_name, size = buf.remaining(), "file: write_buf");
+        async move {
+            let buf = buf.copy_to_bytes(buf.remaining());
+            self.write_chunk(buf).await
+        }
+            .boxed()
     }
 
-    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<()> {
-        todo!()
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<'_, ()> {
+        debug!(file_name = %self.file.file_name, size = buf.len(), "file: write_bytes");
+        async move { self.write_chunk(buf).await }.boxed()
     }
 
-    fn read_bytes(&mut self, count: usize) -> FsFuture<Bytes> {
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
         debug!(
             file_id = %self.file.fid,
             file_name = %self.file.file_name,
@@ -411,46 +1193,435 @@ impl DavFile for QuarkDavFile {
                 .unwrap_or(false);
 
             if !is_valid {
-                let new_url = self.get_download_url().await.unwrap();
+                let new_url = self.get_download_url().await?;
                 self.file.download_url = Some(new_url);
             }
             let download_url = match self.file.download_url.as_ref() {
                 Some(url) => url,
                 None => {
-                        // 详细记录文件信息
-                        println!(
-                        "文件缺少下载URL: {:?}\n文件元数据: {:#?}",
-                        self.file.download_url,
-                        self.file);
+                    error!(file_id = %self.file.fid, file_name = %self.file.file_name, file = ?self.file, "file has no download url");
                     return Err(dav_server::fs::FsError::NotFound);
                 }
             };
 
             if !download_url.is_empty() {
-                let content = self.fs.drive.download(download_url, Some((self.current_pos, count))).await.unwrap();
+                let content = self
+                    .fs
+                    .drive
+                    .download(download_url, Some((self.current_pos, count)))
+                    .await
+                    .map_err(|err| {
+                        error!(file_id = %self.file.fid, file_name = %self.file.file_name, error = %err, "download failed");
+                        fs_error(&err)
+                    })?;
                 self.current_pos += content.len() as u64;
-                return Ok(content);
+                stats::record_download(content.len());
+                Ok(content)
             }else {
-                return Err(FsError::NotFound);
+                Err(FsError::NotFound)
             }
         }
             .boxed()
     }
 
-    fn flush(&mut self) -> FsFuture<()> {
-        todo!()
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        debug!(file_name = %self.file.file_name, "file: flush");
+        async move {
+            if self.upload_state.skip {
+                debug!(file_name = %self.file.file_name, size = self.current_pos, "discarded same size upload");
+                return Ok(());
+            }
+            if !self.file.fid.is_empty() && self.upload_state.pre.is_none() {
+                // opened for reading, nothing to upload
+                return Ok(());
+            }
+            if self.upload_state.committed {
+                return Ok(());
+            }
+            let result = self.commit_upload().await;
+            if result.is_err() {
+                self.abort_upload();
+            }
+            self.remove_placeholder();
+            result
+        }
+            .boxed()
     }
 }
 
 
 
+/// A file being uploaded, listed by the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    pub path: String,
+    pub size: u64,
+}
+
+/// The filesystem served at `/`, either a single drive or one folder per account
+#[derive(Clone)]
+pub enum RootFileSystem {
+    Drive(Box<QuarkDriveFileSystem>),
+    Accounts(AccountsFileSystem),
+}
+
+impl RootFileSystem {
+    pub fn boxed(self) -> Box<dyn dav_server::fs::GuardedFileSystem<()>> {
+        match self {
+            RootFileSystem::Drive(fs) => fs,
+            RootFileSystem::Accounts(fs) => Box::new(fs),
+        }
+    }
+
+    /// A view confined to `root` below the served root, read only if `read_only` is set
+    pub fn scoped(&self, root: &str, read_only: bool) -> Result<Box<dyn dav_server::fs::GuardedFileSystem<()>>> {
+        match self {
+            RootFileSystem::Drive(fs) => {
+                let mut fs = fs.clone();
+                let root = join_root(fs.root(), Path::new(root))?;
+                fs.set_root(&root.to_string_lossy()).set_read_only(read_only);
+                Ok(fs)
+            }
+            RootFileSystem::Accounts(fs) => fs.scoped(root, read_only),
+        }
+    }
+
+    /// The drive of an account, `None` selects the only drive when serving a single account
+    pub fn drive(&self, account: Option<&str>) -> Option<&QuarkDrive> {
+        match (self, account) {
+            (RootFileSystem::Drive(fs), None) => Some(fs.drive()),
+            (RootFileSystem::Drive(_), Some(_)) => None,
+            (RootFileSystem::Accounts(fs), Some(name)) => fs.accounts.get(name).map(|fs| fs.drive()),
+            (RootFileSystem::Accounts(_), None) => None,
+        }
+    }
+
+    /// Invalidate the cached listing of a WebDAV path, or everything below it when `recursive`
+    pub async fn invalidate(&self, path: &str, recursive: bool) -> Result<()> {
+        match self {
+            RootFileSystem::Drive(fs) => fs.invalidate(path, recursive).await,
+            RootFileSystem::Accounts(accounts) => {
+                let path = path.trim_start_matches('/');
+                let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+                if name.is_empty() {
+                    // the top-level folder itself is not cached
+                    if recursive {
+                        join_all(accounts.accounts.values().map(|fs| fs.invalidate("/", true))).await;
+                    }
+                } else {
+                    let fs = accounts
+                        .accounts
+                        .get(name)
+                        .with_context(|| format!("unknown account {}", name))?;
+                    fs.invalidate(rest, recursive).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn uploads(&self) -> Vec<UploadInfo> {
+        match self {
+            RootFileSystem::Drive(fs) => fs.uploads(),
+            RootFileSystem::Accounts(accounts) => accounts
+                .accounts
+                .iter()
+                .flat_map(|(name, fs)| {
+                    fs.uploads().into_iter().map(|upload| UploadInfo {
+                        account: Some(name.clone()),
+                        ..upload
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Routes `/<account>/...` to the filesystem of each Quark account
+#[derive(Clone)]
+pub struct AccountsFileSystem {
+    accounts: BTreeMap<String, QuarkDriveFileSystem>,
+    created: SystemTime,
+}
+
+impl AccountsFileSystem {
+    pub fn new(accounts: BTreeMap<String, QuarkDriveFileSystem>) -> Self {
+        Self {
+            accounts,
+            created: SystemTime::now(),
+        }
+    }
+
+    fn scoped(&self, root: &str, read_only: bool) -> Result<Box<dyn dav_server::fs::GuardedFileSystem<()>>> {
+        let root = normalize_root(root);
+        let mut components = root.components();
+        // skip the root directory
+        components.next();
+        let Some(name) = components.next() else {
+            let mut fs = self.clone();
+            if read_only {
+                for account in fs.accounts.values_mut() {
+                    account.set_read_only(true);
+                }
+            }
+            return Ok(Box::new(fs));
+        };
+        let name = name.as_os_str().to_string_lossy();
+        let mut fs = self
+            .accounts
+            .get(name.as_ref())
+            .with_context(|| format!("unknown account {} in root {}", name, root.display()))?
+            .clone();
+        let account_root = join_root(fs.root(), components.as_path())?;
+        fs.set_root(&account_root.to_string_lossy());
+        if read_only {
+            fs.set_read_only(true);
+        }
+        Ok(Box::new(fs))
+    }
+
+    /// The account filesystem and the path inside it, `None` for the top-level folder
+    fn route(&self, path: &DavPath) -> Result<Option<(&QuarkDriveFileSystem, DavPath)>, FsError> {
+        let url = path.as_url_string();
+        let url = url.trim_start_matches('/');
+        if url.is_empty() {
+            return Ok(None);
+        }
+        let (name, rest) = url.split_once('/').unwrap_or((url, ""));
+        let fs = self.accounts.get(name).ok_or(FsError::NotFound)?;
+        let path = DavPath::new(&format!("/{}", rest)).map_err(|_| FsError::NotFound)?;
+        Ok(Some((fs, path)))
+    }
+
+    /// Like `route`, but refuses the top-level folder and anything directly in it
+    fn route_inner(&self, path: &DavPath) -> Result<(&QuarkDriveFileSystem, DavPath), FsError> {
+        if path.as_url_string().trim_matches('/').split('/').count() < 2 {
+            return Err(FsError::Forbidden);
+        }
+        self.route(path)?.ok_or(FsError::Forbidden)
+    }
+
+    fn route_pair(&self, from: &DavPath, to: &DavPath) -> Result<(&QuarkDriveFileSystem, DavPath, DavPath), FsError> {
+        let (from_fs, from) = self.route_inner(from)?;
+        let (to_fs, to) = self.route_inner(to)?;
+        if !std::ptr::eq(from_fs, to_fs) {
+            // the drives can't copy or move between accounts
+            return Err(FsError::IsRemote);
+        }
+        Ok((from_fs, from, to))
+    }
+}
+
+impl DavFileSystem for AccountsFileSystem {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let (fs, path) = self.route_inner(path)?;
+            fs.open(&path, options).await
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            match self.route(path)? {
+                Some((fs, path)) => fs.read_dir(&path, meta).await,
+                None => {
+                    let entries: Vec<Result<Box<dyn DavDirEntry>, FsError>> = self
+                        .accounts
+                        .keys()
+                        .map(|name| {
+                            Ok(Box::new(AccountDir {
+                                name: name.clone(),
+                                created: self.created,
+                            }) as Box<dyn DavDirEntry>)
+                        })
+                        .collect();
+                    Ok(Box::pin(futures_util::stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            match self.route(path)? {
+                Some((fs, path)) => fs.metadata(&path).await,
+                None => Ok(Box::new(AccountDir {
+                    name: String::new(),
+                    created: self.created,
+                }) as Box<dyn DavMetaData>),
+            }
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (fs, path) = self.route_inner(path)?;
+            fs.create_dir(&path).await
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (fs, path) = self.route_inner(path)?;
+            fs.remove_dir(&path).await
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (fs, path) = self.route_inner(path)?;
+            fs.remove_file(&path).await
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (fs, from, to) = self.route_pair(from, to)?;
+            fs.rename(&from, &to).await
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (fs, from, to) = self.route_pair(from, to)?;
+            fs.copy(&from, &to).await
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(
+        &'a self,
+        _path: &'a DavPath,
+    ) -> std::pin::Pin<Box<dyn futures_util::Future<Output = bool> + Send + 'a>> {
+        Box::pin(ready(true))
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: dav_server::fs::DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            match self.route(path)? {
+                Some((fs, path)) => fs.get_prop(&path, prop).await,
+                None => Err(FsError::NotImplemented),
+            }
+        }
+        .boxed()
+    }
+
+    /// The sum over all accounts, accounts failing to report are left out
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        async move {
+            let quotas = join_all(self.accounts.values().map(|fs| fs.get_quota())).await;
+            let (used, total) = quotas
+                .into_iter()
+                .flatten()
+                .fold((0, 0), |(used, total), (u, t)| (used + u, total + t.unwrap_or(0)));
+            Ok((used, Some(total)))
+        }
+        .boxed()
+    }
+}
+
+/// The folder of an account in the top-level listing
+#[derive(Debug, Clone)]
+struct AccountDir {
+    name: String,
+    created: SystemTime,
+}
+
+impl DavMetaData for AccountDir {
+    fn len(&self) -> u64 {
+        0
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        Ok(self.created)
+    }
+
+    fn is_dir(&self) -> bool {
+        true
+    }
+
+    fn created(&self) -> FsResult<SystemTime> {
+        Ok(self.created)
+    }
+}
+
+impl DavDirEntry for AccountDir {
+    fn name(&self) -> Vec<u8> {
+        self.name.as_bytes().to_vec()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        async move { Ok(Box::new(self.clone()) as Box<dyn DavMetaData>) }.boxed()
+    }
+}
+
+/// Map a failed drive call to the closest WebDAV status: missing files are 404, an expired
+/// cookie is 403, name conflicts are 405, and network errors, rate limits and other API
+/// failures are 500
+fn fs_error(err: &anyhow::Error) -> FsError {
+    match err.chain().find_map(|err| err.downcast_ref::<QuarkError>()) {
+        Some(QuarkError::NotFound(_)) => FsError::NotFound,
+        Some(QuarkError::Exists(_)) => FsError::Exists,
+        Some(QuarkError::Auth(_)) => FsError::Forbidden,
+        _ => FsError::GeneralFailure,
+    }
+}
+
+fn normalize_root(root: &str) -> PathBuf {
+    if root.starts_with('/') {
+        PathBuf::from(root)
+    } else {
+        Path::new("/").join(root)
+    }
+}
+
+/// Join a user root below `base`, a user root can't leave it with `..`
+fn join_root(base: &Path, root: &Path) -> Result<PathBuf> {
+    let mut joined = base.to_path_buf();
+    for component in root.components() {
+        match component {
+            Component::Normal(name) => joined.push(name),
+            Component::RootDir | Component::CurDir => {}
+            _ => anyhow::bail!("invalid root {}", root.display()),
+        }
+    }
+    Ok(joined)
+}
+
+/// Parse md5 and sha1 from a checksum header such as `SHA1:abc MD5:def`
+fn parse_checksums(checksum: &str) -> (Option<String>, Option<String>) {
+    let mut md5 = None;
+    let mut sha1 = None;
+    for item in checksum.split_whitespace() {
+        if let Some((algo, hash)) = item.split_once(':') {
+            if algo.eq_ignore_ascii_case("md5") {
+                md5 = Some(hash.to_string());
+            } else if algo.eq_ignore_ascii_case("sha1") {
+                sha1 = Some(hash.to_string());
+            }
+        }
+    }
+    (md5, sha1)
+}
+
 fn is_url_expired(url: &str) -> bool {
     if let Ok(oss_url) = ::url::Url::parse(url) {
         let expires = oss_url.query_pairs().find_map(|(k, v)| {
-            if k == "Expires" {
-                if let Ok(expires) = v.parse::<u64>() {
-                    return Some(expires);
-                }
+            if k == "Expires" && let Ok(expires) = v.parse::<u64>() {
+                return Some(expires);
             }
             None
         });
@@ -464,4 +1635,617 @@ fn is_url_expired(url: &str) -> bool {
         }
     }
     false
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use dav_server::{memls::MemLs, DavHandler};
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use hyper::body::Frame;
+    use hyper::{Request, StatusCode};
+
+    use super::*;
+    use crate::drive::mock::MockQuark;
+
+    fn new_fs(mock: &MockQuark) -> QuarkDriveFileSystem {
+        let mut fs = QuarkDriveFileSystem::new(mock.drive(), "/".to_string(), 1000, 600).unwrap();
+        // small parts to exercise multipart uploads
+        fs.set_upload_buffer_size(4);
+        fs
+    }
+
+    fn dav(fs: QuarkDriveFileSystem) -> DavHandler {
+        DavHandler::builder()
+            .filesystem(Box::new(fs))
+            .locksystem(MemLs::new())
+            .build_handler()
+    }
+
+    async fn send(dav: &DavHandler, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> (StatusCode, Bytes) {
+        let mut req = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let req = req.body(Full::new(Bytes::copy_from_slice(body))).unwrap();
+        let res = dav.handle(req).await;
+        let status = res.status();
+        (status, res.into_body().collect().await.unwrap().to_bytes())
+    }
+
+    async fn put(dav: &DavHandler, path: &str, body: &[u8]) -> StatusCode {
+        let len = body.len().to_string();
+        send(dav, "PUT", path, &[("Content-Length", &len)], body).await.0
+    }
+
+    /// Aborts are sent in the background
+    async fn wait_aborted(mock: &MockQuark) -> bool {
+        for _ in 0..50 {
+            if mock.uploads().iter().any(|upload| upload.aborted) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_mkcol() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "file", b"");
+        let dav = dav(new_fs(&mock));
+        assert_eq!(send(&dav, "MKCOL", "/dir", &[], b"").await.0, StatusCode::CREATED);
+        assert!(mock.find("/dir").unwrap().dir);
+        assert_eq!(send(&dav, "MKCOL", "/dir/sub", &[], b"").await.0, StatusCode::CREATED);
+        assert!(mock.find("/dir/sub").unwrap().dir);
+        assert_eq!(send(&dav, "MKCOL", "/missing/sub", &[], b"").await.0, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_mkcol_conflict() {
+        let mock = MockQuark::start().await;
+        let dav = dav(new_fs(&mock));
+        // cache the empty root first, the folder is created by another client
+        assert_eq!(send(&dav, "PROPFIND", "/", &[("Depth", "1")], b"").await.0, StatusCode::MULTI_STATUS);
+        mock.add_dir("0", "dir");
+        assert_eq!(send(&dav, "MKCOL", "/dir", &[], b"").await.0, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_delete_to_trash() {
+        let mock = MockQuark::start().await;
+        let dir = mock.add_dir("0", "dir");
+        mock.add_file(&dir, "a.txt", b"a");
+        mock.add_file("0", "b.txt", b"b");
+        let dav = dav(new_fs(&mock));
+        assert_eq!(send(&dav, "DELETE", "/b.txt", &[], b"").await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(&dav, "GET", "/dir/a.txt", &[], b"").await.0, StatusCode::OK);
+        assert_eq!(send(&dav, "DELETE", "/dir", &[], b"").await.0, StatusCode::NO_CONTENT);
+        assert!(mock.list("/").is_empty());
+        // the handler removes the members of a collection one by one
+        assert_eq!(mock.recycled(), ["b.txt", "a.txt", "dir"]);
+        // the cached subtree is gone too
+        assert_eq!(send(&dav, "GET", "/dir/a.txt", &[], b"").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_delete_without_trash() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"a");
+        mock.add_file("0", "b.txt", b"b");
+        // the deleted file is on the third page of the recycle bin
+        mock.fill_recycle_bin(250);
+        let mut fs = new_fs(&mock);
+        fs.set_no_trash(true);
+        let dav = dav(fs);
+        assert_eq!(send(&dav, "DELETE", "/a.txt", &[], b"").await.0, StatusCode::NO_CONTENT);
+        assert_eq!(mock.list("/"), ["b.txt"]);
+        assert_eq!(mock.recycled().len(), 250);
+
+        // trashed but not purged is still a successful delete
+        mock.fail("/1/clouddrive/file/recycle/remove");
+        assert_eq!(send(&dav, "DELETE", "/b.txt", &[], b"").await.0, StatusCode::NO_CONTENT);
+        assert!(mock.list("/").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_refuses_changes() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"a");
+        let mut fs = new_fs(&mock);
+        fs.set_read_only(true);
+        let dav = dav(fs);
+        assert_eq!(send(&dav, "DELETE", "/a.txt", &[], b"").await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(&dav, "MKCOL", "/dir", &[], b"").await.0, StatusCode::FORBIDDEN);
+        assert_eq!(put(&dav, "/b.txt", b"b").await, StatusCode::FORBIDDEN);
+        assert_eq!(mock.list("/"), ["a.txt"]);
+    }
+
+    async fn move_to(dav: &DavHandler, method: &str, from: &str, to: &str) -> StatusCode {
+        send(dav, method, from, &[("Destination", to)], b"").await.0
+    }
+
+    #[tokio::test]
+    async fn test_move() {
+        let mock = MockQuark::start().await;
+        let dir = mock.add_dir("0", "dir");
+        mock.add_file("0", "a.txt", b"a");
+        let dav = dav(new_fs(&mock));
+        assert_eq!(move_to(&dav, "MOVE", "/a.txt", "/b.txt").await, StatusCode::CREATED);
+        assert_eq!(mock.list("/"), ["b.txt", "dir"]);
+        assert_eq!(move_to(&dav, "MOVE", "/b.txt", "/dir/c.txt").await, StatusCode::CREATED);
+        assert_eq!(mock.list("/dir"), ["c.txt"]);
+        assert_eq!(mock.content("/dir/c.txt").unwrap(), b"a");
+
+        mock.add_file(&dir, "d.txt", b"d");
+        assert_eq!(move_to(&dav, "MOVE", "/dir", "/moved").await, StatusCode::CREATED);
+        assert_eq!(mock.list("/moved"), ["c.txt", "d.txt"]);
+        // the cached listing of the old location is gone
+        assert_eq!(send(&dav, "GET", "/dir/d.txt", &[], b"").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_move_overwrite() {
+        let mock = MockQuark::start().await;
+        let dir = mock.add_dir("0", "dir");
+        mock.add_file("0", "a.txt", b"new");
+        mock.add_file("0", "b.txt", b"b");
+        mock.add_file(&dir, "a.txt", b"old");
+        let dav = dav(new_fs(&mock));
+        assert_eq!(move_to(&dav, "MOVE", "/a.txt", "/dir/a.txt").await, StatusCode::NO_CONTENT);
+        assert_eq!(mock.list("/"), ["b.txt", "dir"]);
+        assert_eq!(mock.list("/dir"), ["a.txt"]);
+        assert_eq!(mock.content("/dir/a.txt").unwrap(), b"new");
+        assert_eq!(mock.recycled(), ["a.txt"]);
+
+        assert_eq!(move_to(&dav, "MOVE", "/b.txt", "/dir/a.txt").await, StatusCode::NO_CONTENT);
+        assert_eq!(mock.list("/"), ["dir"]);
+        assert_eq!(mock.content("/dir/a.txt").unwrap(), b"b");
+        assert_eq!(move_to(&dav, "MOVE", "/dir/a.txt", "/dir/a.txt").await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_failed_move_keeps_destination() {
+        let mock = MockQuark::start().await;
+        let dir = mock.add_dir("0", "dir");
+        mock.add_file("0", "a.txt", b"new");
+        mock.add_file(&dir, "a.txt", b"old");
+        mock.fail("/1/clouddrive/file/move");
+        let dav = dav(new_fs(&mock));
+        assert!(!move_to(&dav, "MOVE", "/a.txt", "/dir/a.txt").await.is_success());
+        assert_eq!(mock.list("/"), ["a.txt", "dir"]);
+        assert_eq!(mock.content("/a.txt").unwrap(), b"new");
+        assert_eq!(mock.content("/dir/a.txt").unwrap(), b"old");
+        assert!(mock.recycled().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_copy() {
+        let mock = MockQuark::start().await;
+        mock.add_dir("0", "dir");
+        mock.add_file("0", "a.txt", b"a");
+        let dav = dav(new_fs(&mock));
+        assert_eq!(move_to(&dav, "COPY", "/a.txt", "/b.txt").await, StatusCode::CREATED);
+        assert_eq!(move_to(&dav, "COPY", "/a.txt", "/dir/a.txt").await, StatusCode::CREATED);
+        assert_eq!(move_to(&dav, "COPY", "/a.txt", "/dir/c.txt").await, StatusCode::CREATED);
+        assert_eq!(mock.list("/"), ["a.txt", "b.txt", "dir"]);
+        assert_eq!(mock.list("/dir"), ["a.txt", "c.txt"]);
+        assert_eq!(mock.content("/b.txt").unwrap(), b"a");
+        assert_eq!(mock.content("/dir/c.txt").unwrap(), b"a");
+    }
+
+    #[tokio::test]
+    async fn test_copy_overwrite() {
+        let mock = MockQuark::start().await;
+        let dir = mock.add_dir("0", "dir");
+        mock.add_file("0", "a.txt", b"new");
+        mock.add_file(&dir, "a.txt", b"old");
+        let dav = dav(new_fs(&mock));
+        assert_eq!(move_to(&dav, "COPY", "/a.txt", "/dir/a.txt").await, StatusCode::NO_CONTENT);
+        assert_eq!(mock.list("/dir"), ["a.txt"]);
+        assert_eq!(mock.content("/dir/a.txt").unwrap(), b"new");
+        assert_eq!(mock.recycled(), ["a.txt"]);
+        assert_eq!(mock.content("/a.txt").unwrap(), b"new");
+    }
+
+    /// Names in a PROPFIND listing of a folder, the folder itself first and its entries sorted
+    async fn propfind_names(dav: &DavHandler, path: &str) -> Vec<String> {
+        let (_, body) = send(dav, "PROPFIND", path, &[("Depth", "1")], b"").await;
+        let body = String::from_utf8_lossy(&body);
+        let mut names: Vec<String> = body
+            .split("<D:href>")
+            .skip(1)
+            .filter_map(|href| href.split_once("</D:href>"))
+            .map(|(href, _)| href.trim_end_matches('/').rsplit('/').next().unwrap().to_string())
+            .collect();
+        names[1..].sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_copy_collection() {
+        let mock = MockQuark::start().await;
+        let dir = mock.add_dir("0", "dir");
+        mock.add_file(&dir, "a.txt", b"a");
+        let sub = mock.add_dir(&dir, "sub");
+        mock.add_file(&sub, "b.txt", b"b");
+        let dav = dav(new_fs(&mock));
+        // cache the listings the copy changes
+        assert_eq!(propfind_names(&dav, "/").await, ["", "dir"]);
+
+        let status = send(&dav, "COPY", "/dir", &[("Destination", "/copy"), ("Depth", "infinity")], b"").await.0;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(mock.list("/"), ["copy", "dir"]);
+        assert_eq!(mock.list("/copy"), ["a.txt", "sub"]);
+        assert_eq!(mock.list("/copy/sub"), ["b.txt"]);
+        assert_eq!(mock.content("/copy/sub/b.txt").unwrap(), b"b");
+        assert_eq!(mock.list("/dir/sub"), ["b.txt"]);
+        assert_eq!(propfind_names(&dav, "/").await, ["", "copy", "dir"]);
+        assert_eq!(propfind_names(&dav, "/copy/sub/").await, ["sub", "b.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_copy_collection_overwrite() {
+        let mock = MockQuark::start().await;
+        let dir = mock.add_dir("0", "dir");
+        mock.add_file(&dir, "a.txt", b"new");
+        let sub = mock.add_dir(&dir, "sub");
+        mock.add_file(&sub, "b.txt", b"b");
+        let old = mock.add_dir("0", "old");
+        mock.add_file(&old, "a.txt", b"old");
+        mock.add_file(&old, "stale.txt", b"stale");
+        let dav = dav(new_fs(&mock));
+        assert_eq!(propfind_names(&dav, "/old/").await, ["old", "a.txt", "stale.txt"]);
+
+        let headers = [("Destination", "/old"), ("Depth", "infinity"), ("Overwrite", "T")];
+        assert_eq!(send(&dav, "COPY", "/dir", &headers, b"").await.0, StatusCode::NO_CONTENT);
+        assert_eq!(mock.list("/old"), ["a.txt", "sub"]);
+        assert_eq!(mock.content("/old/a.txt").unwrap(), b"new");
+        assert_eq!(mock.list("/old/sub"), ["b.txt"]);
+        assert_eq!(propfind_names(&dav, "/old/").await, ["old", "a.txt", "sub"]);
+
+        // a file copied over a folder replaces it
+        assert_eq!(
+            send(&dav, "COPY", "/dir/a.txt", &[("Destination", "/old"), ("Overwrite", "T")], b"").await.0,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(mock.content("/old").unwrap(), b"new");
+        assert_eq!(propfind_names(&dav, "/").await, ["", "dir", "old"]);
+        assert_eq!(send(&dav, "GET", "/old", &[], b"").await.1.as_ref(), b"new");
+
+        // without overwriting the folder is kept
+        let headers = [("Destination", "/old"), ("Depth", "infinity"), ("Overwrite", "F")];
+        assert_eq!(send(&dav, "COPY", "/dir", &headers, b"").await.0, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(mock.content("/old").unwrap(), b"new");
+    }
+
+    #[tokio::test]
+    async fn test_failed_copy_keeps_destination() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"new");
+        mock.add_file("0", "b.txt", b"old");
+        mock.fail("/1/clouddrive/file/copy");
+        let dav = dav(new_fs(&mock));
+        assert!(!move_to(&dav, "COPY", "/a.txt", "/b.txt").await.is_success());
+        assert_eq!(mock.list("/"), ["a.txt", "b.txt"]);
+        assert_eq!(mock.content("/b.txt").unwrap(), b"old");
+        assert!(mock.recycled().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_instant_upload() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"0123456789");
+        mock.add_file("0", "b.txt", b"012");
+        let dav = dav(new_fs(&mock));
+        // the hashes of a small file are known before uploading any part
+        assert_eq!(put(&dav, "/small.txt", b"012").await, StatusCode::CREATED);
+        // the client hash is verified before the pre-check
+        let sha1 = "SHA1:87acec17cd9dcd20a716cc2cf67417b71c8a7016";
+        let (status, _) = send(
+            &dav,
+            "PUT",
+            "/c.txt",
+            &[("Content-Length", "10"), ("OC-Checksum", sha1)],
+            b"0123456789",
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(mock.content("/small.txt").unwrap(), b"012");
+        assert_eq!(mock.content("/c.txt").unwrap(), b"0123456789");
+        let uploads = mock.uploads();
+        assert!(uploads.iter().all(|upload| upload.instant && upload.parts.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"0123456789");
+        let dav = dav(new_fs(&mock));
+        let wrong_sha1 = "SHA1:0000000000000000000000000000000000000000";
+        let wrong_md5 = "MD5:00000000000000000000000000000000";
+        for checksum in [wrong_sha1, wrong_md5] {
+            let (status, _) = send(
+                &dav,
+                "PUT",
+                "/b.txt",
+                &[("Content-Length", "10"), ("OC-Checksum", checksum)],
+                b"0123456789",
+            )
+            .await;
+            assert!(!status.is_success());
+        }
+        assert_eq!(mock.list("/"), ["a.txt"]);
+        // nothing was pre-uploaded with an unverified hash
+        assert!(mock.uploads().is_empty());
+
+        let md5 = "MD5:781e5e245d69b566979b86e28d23f2c7";
+        let (status, _) = send(
+            &dav,
+            "PUT",
+            "/b.txt",
+            &[("Content-Length", "10"), ("OC-Checksum", md5)],
+            b"0123456789",
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_quota() {
+        let mock = MockQuark::start().await;
+        let dav = dav(new_fs(&mock));
+        let body = br#"<?xml version="1.0"?>
+<D:propfind xmlns:D="DAV:"><D:prop><D:quota-used-bytes/><D:quota-available-bytes/></D:prop></D:propfind>"#;
+        let (status, body) = send(&dav, "PROPFIND", "/", &[("Depth", "0")], body).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("quota-used-bytes>100<"), "{}", body);
+        assert!(body.contains("quota-available-bytes>900<"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_skip_upload_same_size() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"old");
+        let mut fs = new_fs(&mock);
+        fs.set_skip_upload_same_size(true);
+        let dav = dav(fs);
+        assert_eq!(put(&dav, "/a.txt", b"new").await, StatusCode::NO_CONTENT);
+        assert_eq!(mock.content("/a.txt").unwrap(), b"old");
+        assert!(mock.uploads().is_empty());
+
+        assert_eq!(put(&dav, "/a.txt", b"newer").await, StatusCode::NO_CONTENT);
+        assert_eq!(mock.content("/a.txt").unwrap(), b"newer");
+    }
+
+    #[tokio::test]
+    async fn test_uploading_file_is_listed() {
+        let mock = MockQuark::start().await;
+        let dav = dav(new_fs(&mock));
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Frame<Bytes>, std::io::Error>>(1);
+        let body = StreamBody::new(futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|frame| (frame, rx))
+        }));
+        let req = Request::builder()
+            .method("PUT")
+            .uri("/a.txt")
+            .header("Content-Length", "10")
+            .body(body)
+            .unwrap();
+        let upload = tokio::spawn({
+            let dav = dav.clone();
+            async move { dav.handle(req).await.status() }
+        });
+        tx.send(Ok(Frame::data(Bytes::from_static(b"01234")))).await.unwrap();
+
+        let mut listed = false;
+        for _ in 0..50 {
+            let (_, body) = send(&dav, "PROPFIND", "/", &[("Depth", "1")], b"").await;
+            if String::from_utf8_lossy(&body).contains("/a.txt") {
+                listed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(listed);
+        assert!(mock.find("/a.txt").is_none());
+
+        tx.send(Ok(Frame::data(Bytes::from_static(b"56789")))).await.unwrap();
+        drop(tx);
+        assert_eq!(upload.await.unwrap(), StatusCode::CREATED);
+        assert_eq!(mock.content("/a.txt").unwrap(), b"0123456789");
+        let (_, body) = send(&dav, "PROPFIND", "/", &[("Depth", "1")], b"").await;
+        assert_eq!(String::from_utf8_lossy(&body).matches("/a.txt").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_put_uploads_parts() {
+        let mock = MockQuark::start().await;
+        let dav = dav(new_fs(&mock));
+        assert_eq!(put(&dav, "/a.txt", b"0123456789").await, StatusCode::CREATED);
+        assert_eq!(mock.content("/a.txt").unwrap(), b"0123456789");
+        let uploads = mock.uploads();
+        assert_eq!(uploads[0].parts.len(), 3);
+        assert_eq!(uploads[0].size, 10);
+
+        let (status, body) = send(&dav, "GET", "/a.txt", &[], b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_put_without_length() {
+        let mock = MockQuark::start().await;
+        let dav = dav(new_fs(&mock));
+        let (status, _) = send(&dav, "PUT", "/a.txt", &[], b"0123456789").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(mock.content("/a.txt").unwrap(), b"0123456789");
+        // pre-uploaded with the real size once everything was spooled
+        assert_eq!(mock.uploads()[0].size, 10);
+    }
+
+    #[tokio::test]
+    async fn test_put_short_body_is_not_committed() {
+        let mock = MockQuark::start().await;
+        let dav = dav(new_fs(&mock));
+        let (status, _) = send(&dav, "PUT", "/a.txt", &[("Content-Length", "20")], b"0123456789").await;
+        assert!(!status.is_success());
+        assert!(mock.find("/a.txt").is_none());
+        assert!(wait_aborted(&mock).await);
+
+        let (status, _) = send(&dav, "PUT", "/b.txt", &[("Content-Length", "3")], b"0123456789").await;
+        assert!(!status.is_success());
+        assert!(mock.find("/b.txt").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_put_broken_body_aborts_upload() {
+        let mock = MockQuark::start().await;
+        let dav = dav(new_fs(&mock));
+        let frames: Vec<Result<Frame<Bytes>, std::io::Error>> = vec![
+            Ok(Frame::data(Bytes::from_static(b"01234567"))),
+            Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed")),
+        ];
+        let req = Request::builder()
+            .method("PUT")
+            .uri("/a.txt")
+            .header("Content-Length", "10")
+            .body(StreamBody::new(futures_util::stream::iter(frames)))
+            .unwrap();
+        assert!(!dav.handle(req).await.status().is_success());
+        assert!(mock.find("/a.txt").is_none());
+        assert!(wait_aborted(&mock).await);
+    }
+
+    #[tokio::test]
+    async fn test_put_overwrite() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"old");
+        let dav = dav(new_fs(&mock));
+        assert_eq!(put(&dav, "/a.txt", b"new content").await, StatusCode::NO_CONTENT);
+        assert_eq!(mock.list("/"), ["a.txt"]);
+        assert_eq!(mock.content("/a.txt").unwrap(), b"new content");
+        assert_eq!(mock.recycled(), ["a.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_seek_on_upload() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"old content");
+        let fs = new_fs(&mock);
+        let options = OpenOptions {
+            write: true,
+            create: true,
+            truncate: true,
+            size: Some(3),
+            ..Default::default()
+        };
+        let mut file = fs.open(&DavPath::new("/b.txt").unwrap(), options).await.unwrap();
+        assert_eq!(file.seek(SeekFrom::Current(0)).await.unwrap(), 0);
+        assert!(matches!(file.seek(SeekFrom::Start(2)).await, Err(FsError::NotImplemented)));
+        drop(file);
+
+        // a partial PUT seeks before writing
+        let dav = dav(fs);
+        let headers = [("Content-Length", "3"), ("Content-Range", "bytes 4-6/11")];
+        let (status, _) = send(&dav, "PUT", "/a.txt", &headers, b"new").await;
+        assert!(!status.is_success());
+        assert_eq!(mock.content("/a.txt").unwrap(), b"old content");
+    }
+
+    #[tokio::test]
+    async fn test_failed_overwrite_keeps_old_file() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"old");
+        mock.fail("/1/clouddrive/file/upload/finish");
+        let mut fs = new_fs(&mock);
+        fs.set_no_trash(true);
+        let dav = dav(fs);
+        assert!(!put(&dav, "/a.txt", b"new content").await.is_success());
+        assert_eq!(mock.list("/"), ["a.txt"]);
+        assert_eq!(mock.content("/a.txt").unwrap(), b"old");
+        assert!(mock.recycled().is_empty());
+    }
+
+    fn accounts_dav(fs: Box<dyn dav_server::fs::GuardedFileSystem<()>>) -> DavHandler {
+        DavHandler::builder().filesystem(fs).locksystem(MemLs::new()).build_handler()
+    }
+
+    #[tokio::test]
+    async fn test_accounts() {
+        let alice = MockQuark::start().await;
+        alice.add_file("0", "a.txt", b"a");
+        let bob = MockQuark::start().await;
+        bob.add_file("0", "b.txt", b"b");
+        let fs = RootFileSystem::Accounts(AccountsFileSystem::new(BTreeMap::from([
+            ("alice".to_string(), new_fs(&alice)),
+            ("bob".to_string(), new_fs(&bob)),
+        ])));
+        let dav = accounts_dav(fs.clone().boxed());
+
+        let (status, body) = send(&dav, "PROPFIND", "/", &[("Depth", "1")], b"").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("<D:href>/alice/</D:href>") && body.contains("<D:href>/bob/</D:href>"), "{}", body);
+
+        let (status, body) = send(&dav, "GET", "/alice/a.txt", &[], b"").await;
+        assert_eq!((status, body.as_ref()), (StatusCode::OK, b"a".as_ref()));
+        assert_eq!(send(&dav, "GET", "/bob/a.txt", &[], b"").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(&dav, "GET", "/carol/a.txt", &[], b"").await.0, StatusCode::NOT_FOUND);
+
+        assert_eq!(put(&dav, "/bob/new.txt", b"new").await, StatusCode::CREATED);
+        assert_eq!(bob.list("/"), ["b.txt", "new.txt"]);
+        assert_eq!(alice.list("/"), ["a.txt"]);
+
+        // the top-level folder belongs to no account
+        assert_eq!(put(&dav, "/top.txt", b"top").await, StatusCode::FORBIDDEN);
+        assert_eq!(send(&dav, "MKCOL", "/carol", &[], b"").await.0, StatusCode::FORBIDDEN);
+
+        // the drives can't move files between accounts
+        assert_eq!(move_to(&dav, "MOVE", "/alice/a.txt", "/bob/a.txt").await, StatusCode::BAD_GATEWAY);
+        assert_eq!(alice.list("/"), ["a.txt"]);
+        assert_eq!(move_to(&dav, "MOVE", "/alice/a.txt", "/alice/c.txt").await, StatusCode::CREATED);
+        assert_eq!(alice.list("/"), ["c.txt"]);
+
+        // the quota is summed over the accounts
+        let body = br#"<?xml version="1.0"?>
+<D:propfind xmlns:D="DAV:"><D:prop><D:quota-used-bytes/></D:prop></D:propfind>"#;
+        let (_, body) = send(&dav, "PROPFIND", "/", &[("Depth", "0")], body).await;
+        assert!(String::from_utf8_lossy(&body).contains("quota-used-bytes>200<"));
+
+        // a user confined to one account
+        let dav = accounts_dav(fs.scoped("/bob", true).unwrap());
+        let (status, body) = send(&dav, "GET", "/b.txt", &[], b"").await;
+        assert_eq!((status, body.as_ref()), (StatusCode::OK, b"b".as_ref()));
+        assert_eq!(put(&dav, "/c.txt", b"c").await, StatusCode::FORBIDDEN);
+        assert!(fs.scoped("/carol", false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_user_roots_are_below_the_root() {
+        let mock = MockQuark::start().await;
+        let media = mock.add_dir("0", "media");
+        mock.add_file(&media, "m.txt", b"m");
+        let media_alice = mock.add_dir(&media, "alice");
+        mock.add_file(&media_alice, "a.txt", b"media alice");
+        let alice = mock.add_dir("0", "alice");
+        mock.add_file(&alice, "a.txt", b"drive alice");
+        mock.add_file("0", "top.txt", b"top");
+        let mut fs = new_fs(&mock);
+        fs.set_root("/media");
+        let fs = RootFileSystem::Drive(Box::new(fs));
+
+        let dav = accounts_dav(fs.scoped("/", false).unwrap());
+        assert_eq!(send(&dav, "GET", "/m.txt", &[], b"").await.0, StatusCode::OK);
+        assert_eq!(send(&dav, "GET", "/top.txt", &[], b"").await.0, StatusCode::NOT_FOUND);
+
+        let dav = accounts_dav(fs.scoped("/alice", false).unwrap());
+        let (status, body) = send(&dav, "GET", "/a.txt", &[], b"").await;
+        assert_eq!((status, body.as_ref()), (StatusCode::OK, b"media alice".as_ref()));
+        assert_eq!(put(&dav, "/b.txt", b"b").await, StatusCode::CREATED);
+        assert_eq!(mock.list("/media/alice"), ["a.txt", "b.txt"]);
+        assert_eq!(mock.list("/alice"), ["a.txt"]);
+
+        assert!(fs.scoped("/../alice", false).is_err());
+        assert!(fs.scoped("alice/../..", false).is_err());
+    }
+}
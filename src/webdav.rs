@@ -1,81 +1,312 @@
+use std::collections::HashMap;
 use std::future::Future;
 use std::io;
-use std::net::ToSocketAddrs;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use anyhow::Result;
+use dashmap::DashMap;
+use futures_util::future::select_all;
 use dav_server::{body::Body, DavConfig, DavHandler};
 use headers::{authorization::Basic, Authorization, HeaderMapExt};
+use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
 use hyper::service::Service;
 use hyper::{Request, Response};
 use hyper_util::{
     rt::{TokioExecutor, TokioIo},
-    server::conn::auto,
+    server::{conn::auto, graceful::{GracefulShutdown, Watcher}},
 };
-use tokio::net::TcpListener;
-use tracing::{error, info};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{error, info, warn};
 
-// #[cfg(feature = "rustls-tls")]
+use crate::auth::{digest::DigestOutcome, AuthScheme, DigestAuth, LoginGuard, Password, VerifiedCache};
+use crate::listener::Listener;
+use crate::stats;
+
+#[cfg(feature = "rustls-tls")]
 use {
     std::fs::File,
+    std::io::BufReader,
     std::path::Path,
-    std::sync::Arc,
+    std::sync::RwLock,
+    anyhow::Context,
+    tokio_rustls::{rustls::{self, ServerConfig}, TlsAcceptor},
 };
 
+const VERIFIED_CACHE_TTL: u64 = 300;
+#[cfg(feature = "rustls-tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause before accepting again after a failed accept
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+pub const REALM: &str = "quarkdriver-webdav";
+
 pub struct WebDavServer {
-    pub host: String,
-    pub port: u16,
-    pub auth_user: Option<String>,
-    pub auth_password: Option<String>,
-    pub tls_config: Option<(PathBuf, PathBuf)>,
+    pub listeners: Vec<Listener>,
+    pub users: HashMap<String, WebDavUser>,
+    pub auth_scheme: AuthScheme,
+    pub login_guard: LoginGuard,
+    pub tls_config: Option<TlsConfig>,
+    /// How long to wait for active connections to finish on shutdown
+    pub shutdown_timeout: Duration,
+    pub connections: Connections,
     pub handler: DavHandler,
 }
 
-impl WebDavServer {
-    pub async fn serve(self) -> Result<()> {
-        let addr = (self.host, self.port)
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+/// Open client connections, listed by the admin API
+#[derive(Clone, Default)]
+pub struct Connections {
+    next_id: Arc<AtomicU64>,
+    active: Arc<DashMap<u64, ConnectionInfo>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub remote_addr: SocketAddr,
+    pub connected_at: SystemTime,
+}
+
+impl Connections {
+    fn open(&self, remote_addr: SocketAddr) -> ConnectionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.active.insert(id, ConnectionInfo {
+            id,
+            remote_addr,
+            connected_at: SystemTime::now(),
+        });
+        ConnectionHandle {
+            id,
+            connections: self.clone(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self.active.iter().map(|entry| entry.value().clone()).collect();
+        connections.sort_by_key(|conn| conn.id);
+        connections
+    }
+}
+
+/// Removes the connection from the list when dropped
+struct ConnectionHandle {
+    id: u64,
+    connections: Connections,
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        self.connections.active.remove(&self.id);
+    }
+}
+
+/// An authenticated user with a handler confined to the user's root directory
+#[derive(Clone)]
+pub struct WebDavUser {
+    pub password: Password,
+    pub handler: DavHandler,
+}
 
+/// TLS certificate and private key, reloadable at runtime
+#[derive(Clone)]
+pub struct TlsConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    #[cfg(feature = "rustls-tls")]
+    server_config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsConfig {
+    #[cfg(feature = "rustls-tls")]
+    pub fn new(cert: PathBuf, key: PathBuf) -> Result<Self> {
+        let server_config = load_server_config(&cert, &key)?;
+        Ok(Self {
+            cert,
+            key,
+            server_config: Arc::new(RwLock::new(server_config)),
+        })
+    }
+
+    #[cfg(not(feature = "rustls-tls"))]
+    pub fn new(cert: PathBuf, key: PathBuf) -> Result<Self> {
+        let _ = (cert, key);
+        anyhow::bail!("TLS is not supported, rebuild with the `rustls-tls` feature")
+    }
+
+    /// Reload the certificate and private key from disk
+    #[cfg(feature = "rustls-tls")]
+    pub fn reload(&self) -> Result<()> {
+        let server_config = load_server_config(&self.cert, &self.key)?;
+        *self.server_config.write().unwrap() = server_config;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "rustls-tls"))]
+    pub fn reload(&self) -> Result<()> {
+        let _ = (&self.cert, &self.key);
+        Ok(())
+    }
+
+    #[cfg(feature = "rustls-tls")]
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server_config.read().unwrap().clone())
+    }
+}
+
+#[cfg(feature = "rustls-tls")]
+fn load_server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid TLS certificate file {}", cert.display()))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))
+        .with_context(|| format!("invalid TLS private key file {}", key.display()))?
+        .with_context(|| format!("no private key found in {}", key.display()))?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+impl WebDavServer {
+    /// Serve until `shutdown` resolves, then drain active connections
+    pub async fn serve<F>(self, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        // unknown users are checked against a hash as slow as the configured ones
+        let unknown_user = self
+            .users
+            .values()
+            .map(|user| &user.password)
+            .max_by_key(|password| password.plain().is_none())
+            .map(Password::dummy)
+            .unwrap_or_else(|| Password::Plain(String::new()));
         let make_svc = MakeSvc {
-            auth_user: self.auth_user.clone(),
-            auth_password: self.auth_password.clone(),
+            users: Arc::new(self.users),
+            unknown_user,
+            auth_scheme: self.auth_scheme,
+            digest_auth: DigestAuth::new(REALM),
+            login_guard: self.login_guard,
+            verified: VerifiedCache::new(Duration::from_secs(VERIFIED_CACHE_TTL)),
             handler: self.handler.clone(),
         };
 
-        let listener = TcpListener::bind(&addr).await?;
-        info!("listening on http://{}", listener.local_addr()?);
+        let scheme = if self.tls_config.is_some() { "https" } else { "http" };
+        for listener in &self.listeners {
+            info!("listening on {}", listener.url(scheme));
+        }
 
+        let graceful = GracefulShutdown::new();
+        tokio::pin!(shutdown);
         loop {
-            let (tcp, _) = listener.accept().await?;
-            let io = TokioIo::new(tcp);
+            let accept = select_all(self.listeners.iter().map(|listener| Box::pin(listener.accept())));
+            let accepted = tokio::select! {
+                (conn, index, _) = accept => conn.map_err(|err| (index, err)),
+                _ = &mut shutdown => break,
+            };
+            let (stream, remote_addr) = match accepted {
+                Ok(conn) => conn,
+                // the client went away before it was accepted
+                Err((_, err)) if is_connection_error(&err) => continue,
+                Err((index, err)) if is_listener_broken(&err) => {
+                    return Err(anyhow::Error::new(err).context(format!("listener {} failed", self.listeners[index])));
+                }
+                Err((index, err)) => {
+                    // out of file descriptors or buffers, wait for connections to close instead of spinning
+                    error!(listener = %self.listeners[index], error = %err, "accept failed");
+                    tokio::select! {
+                        _ = tokio::time::sleep(ACCEPT_ERROR_DELAY) => continue,
+                        _ = &mut shutdown => break,
+                    }
+                }
+            };
             let make_svc = make_svc.clone();
+            let watcher = graceful.watcher();
+            let conn = self.connections.open(remote_addr);
 
-            tokio::spawn(async move {
-                let service = match make_svc.call(()).await {
-                    Ok(service) => service,
-                    Err(_) => return,
-                };
+            #[cfg(feature = "rustls-tls")]
+            if let Some(tls_config) = &self.tls_config {
+                let acceptor = tls_config.acceptor();
+                tokio::spawn(async move {
+                    // don't let clients that never finish the handshake hold a connection
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => serve_connection(stream, remote_addr, make_svc, watcher, conn).await,
+                        Ok(Err(e)) => error!("TLS handshake error: {}", e),
+                        Err(_) => warn!(remote_addr = %remote_addr, "TLS handshake timed out"),
+                    }
+                });
+                continue;
+            }
 
-                if let Err(e) = auto::Builder::new(TokioExecutor::new())
-                    .serve_connection(io, service)
-                    .await
-                {
-                    error!("HTTP serve error: {}", e);
-                }
-            });
+            tokio::spawn(serve_connection(stream, remote_addr, make_svc, watcher, conn));
         }
 
-        // 循环会持续运行，实际不会到达这里
+        // stop accepting new connections while the active ones drain
+        drop(self.listeners);
+        info!(
+            connections = graceful.count(),
+            timeout = ?self.shutdown_timeout,
+            "shutting down, waiting for active connections"
+        );
+        tokio::select! {
+            _ = graceful.shutdown() => info!("all connections closed"),
+            _ = tokio::time::sleep(self.shutdown_timeout) => {
+                warn!("shutdown timed out, aborting remaining connections")
+            }
+        }
         Ok(())
     }
 }
 
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Errors that won't go away by accepting again, such as a socket that isn't listening
+fn is_listener_broken(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported)
+}
+
+async fn serve_connection<I>(
+    io: I,
+    remote_addr: SocketAddr,
+    make_svc: MakeSvc,
+    watcher: Watcher,
+    _conn: ConnectionHandle,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let _guard = stats::ConnectionGuard::open();
+    let service = match make_svc.call(remote_addr).await {
+        Ok(service) => service,
+        Err(_) => return,
+    };
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection(TokioIo::new(io), service);
+    if let Err(e) = watcher.watch(conn).await {
+        error!("HTTP serve error: {}", e);
+    }
+}
+
 #[derive(Clone)]
 pub struct QuarkDriveWebDav {
-    auth_user: Option<String>,
-    auth_password: Option<String>,
+    remote_addr: SocketAddr,
+    users: Arc<HashMap<String, WebDavUser>>,
+    unknown_user: Password,
+    auth_scheme: AuthScheme,
+    digest_auth: DigestAuth,
+    login_guard: LoginGuard,
+    verified: VerifiedCache,
     handler: DavHandler,
 }
 
@@ -85,63 +316,534 @@ impl Service<Request<hyper::body::Incoming>> for QuarkDriveWebDav {
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<hyper::body::Incoming>) -> Self::Future {
-        let should_auth = self.auth_user.is_some() && self.auth_password.is_some();
-        let dav_server = self.handler.clone();
-        let auth_user = self.auth_user.clone();
-        let auth_pwd = self.auth_password.clone();
+        let webdav = self.clone();
+        let method = req.method().to_string();
+        let start = Instant::now();
 
         Box::pin(async move {
-            if should_auth {
-                let auth_user = auth_user.unwrap();
-                let auth_pwd = auth_pwd.unwrap();
-
-                let user = match req.headers().typed_get::<Authorization<Basic>>() {
-                    Some(Authorization(basic))
-                    if basic.username() == auth_user && basic.password() == auth_pwd =>
-                        {
-                            basic.username().to_string()
-                        }
-                    _ => {
-                        return Ok(Response::builder()
-                            .status(401)
-                            .header("WWW-Authenticate", "Basic realm=\"quarkdriver-webdav\"")
-                            .body(Body::from("Authentication required"))
-                            .unwrap());
-                    }
-                };
+            let res = webdav.handle(req).await;
+            if let Ok(res) = &res {
+                stats::record_request(&method, res.status().as_u16(), start.elapsed());
+            }
+            res
+        })
+    }
+}
 
+impl QuarkDriveWebDav {
+    async fn handle(self, req: Request<hyper::body::Incoming>) -> Result<Response<Body>, hyper::Error> {
+        if self.users.is_empty() {
+            return Ok(self.handler.handle(req).await);
+        }
+        let ip = self.login_guard.client_ip(self.remote_addr.ip(), req.headers());
+        let attempt = match ip.map(|ip| self.login_guard.check(ip)).transpose() {
+            Ok(attempt) => attempt,
+            Err(wait) => return Ok(too_many_requests(wait)),
+        };
+        let authenticated = match self.auth_scheme {
+            AuthScheme::Basic => authenticate_basic(&req, &self.users, &self.unknown_user, &self.verified).await,
+            AuthScheme::Digest => authenticate_digest(&req, &self.users, &self.digest_auth),
+        };
+        match authenticated {
+            Ok((user, dav_server)) => {
+                if let Some(attempt) = attempt {
+                    attempt.succeeded();
+                }
                 let config = DavConfig::new().principal(user);
                 Ok(dav_server.handle_with(config, req).await)
-            } else {
-                Ok(dav_server.handle(req).await)
             }
-        })
+            Err(err) => {
+                if let (AuthError::Invalid, Some(attempt)) = (&err, attempt) {
+                    attempt.failed();
+                }
+                Ok(unauthorized(self.auth_scheme, &self.digest_auth, err == AuthError::Stale))
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum AuthError {
+    Missing,
+    Stale,
+    Invalid,
+}
+
+async fn authenticate_basic<B>(
+    req: &Request<B>,
+    users: &HashMap<String, WebDavUser>,
+    unknown_user: &Password,
+    verified: &VerifiedCache,
+) -> Result<(String, DavHandler), AuthError> {
+    let Some(Authorization(basic)) = req.headers().typed_get::<Authorization<Basic>>() else {
+        return Err(AuthError::Missing);
+    };
+    match users.get(basic.username()) {
+        Some(user) if verified.verify(&user.password, basic.username(), basic.password()).await => {
+            Ok((basic.username().to_string(), user.handler.clone()))
+        }
+        Some(_) => Err(AuthError::Invalid),
+        None => {
+            // check anyway so unknown users take as long as wrong passwords
+            let _ = unknown_user.verify_async(basic.password()).await;
+            Err(AuthError::Invalid)
+        }
     }
 }
 
+fn authenticate_digest<B>(
+    req: &Request<B>,
+    users: &HashMap<String, WebDavUser>,
+    digest_auth: &DigestAuth,
+) -> Result<(String, DavHandler), AuthError> {
+    let Some(header) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.starts_with("Digest "))
+    else {
+        return Err(AuthError::Missing);
+    };
+    let request_uri = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let outcome = digest_auth.verify(header, req.method().as_str(), request_uri, |username| {
+        users
+            .get(username)
+            .and_then(|user| user.password.plain())
+            .map(|password| password.to_string())
+    });
+    match outcome {
+        DigestOutcome::Authenticated(username) => {
+            let handler = users.get(&username).ok_or(AuthError::Invalid)?.handler.clone();
+            Ok((username, handler))
+        }
+        DigestOutcome::Stale => Err(AuthError::Stale),
+        DigestOutcome::Invalid => Err(AuthError::Invalid),
+    }
+}
+
+fn too_many_requests(wait: Duration) -> Response<Body> {
+    Response::builder()
+        .status(429)
+        .header("Retry-After", wait.as_secs().max(1).to_string())
+        .body(Body::from("Too many failed login attempts"))
+        .unwrap()
+}
+
+fn unauthorized(auth_scheme: AuthScheme, digest_auth: &DigestAuth, stale: bool) -> Response<Body> {
+    let mut builder = Response::builder().status(401);
+    match auth_scheme {
+        AuthScheme::Basic => {
+            builder = builder.header(WWW_AUTHENTICATE, format!("Basic realm=\"{}\"", REALM));
+        }
+        AuthScheme::Digest => {
+            for challenge in digest_auth.challenges(stale) {
+                builder = builder.header(WWW_AUTHENTICATE, challenge);
+            }
+        }
+    }
+    builder.body(Body::from("Authentication required")).unwrap()
+}
+
 #[derive(Clone)]
 pub struct MakeSvc {
-    pub auth_user: Option<String>,
-    pub auth_password: Option<String>,
+    pub users: Arc<HashMap<String, WebDavUser>>,
+    /// Checked instead when the user is unknown
+    pub unknown_user: Password,
+    pub auth_scheme: AuthScheme,
+    pub digest_auth: DigestAuth,
+    pub login_guard: LoginGuard,
+    pub verified: VerifiedCache,
     pub handler: DavHandler,
 }
 
-impl Service<()> for MakeSvc {
+impl Service<SocketAddr> for MakeSvc {
     type Response = QuarkDriveWebDav;
     type Error = hyper::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, _: ()) -> Self::Future {
-        let auth_user = self.auth_user.clone();
-        let auth_password = self.auth_password.clone();
+    fn call(&self, remote_addr: SocketAddr) -> Self::Future {
+        let users = self.users.clone();
+        let unknown_user = self.unknown_user.clone();
+        let auth_scheme = self.auth_scheme;
+        let digest_auth = self.digest_auth.clone();
+        let login_guard = self.login_guard.clone();
+        let verified = self.verified.clone();
         let handler = self.handler.clone();
 
         Box::pin(async move {
             Ok(QuarkDriveWebDav {
-                auth_user,
-                auth_password,
+                remote_addr,
+                users,
+                unknown_user,
+                auth_scheme,
+                digest_auth,
+                login_guard,
+                verified,
                 handler,
             })
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use dav_server::fs::GuardedFileSystem;
+    use dav_server::ls::DavLockSystem;
+    use dav_server::memls::MemLs;
+    use reqwest::StatusCode;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::auth::LoginGuardConfig;
+    use crate::drive::mock::MockQuark;
+    use crate::listener::ListenAddr;
+    use crate::lock::ScopedLs;
+    use crate::vfs::{QuarkDriveFileSystem, RootFileSystem};
+
+    const LOCK_INFO: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"#;
+
+    fn handler(fs: Box<dyn GuardedFileSystem<()>>, locks: Box<dyn DavLockSystem>) -> DavHandler {
+        DavHandler::builder().filesystem(fs).locksystem(locks).build_handler()
+    }
+
+    /// A server for the mock drive, `users` are `(username, password, root, read_only)`
+    fn new_server(mock: &MockQuark, users: &[(&str, &str, &str, bool)]) -> WebDavServer {
+        let fs = QuarkDriveFileSystem::new(mock.drive(), "/".to_string(), 1000, 600).unwrap();
+        let fs = RootFileSystem::Drive(Box::new(fs));
+        let locks = MemLs::new();
+        let users = users
+            .iter()
+            .map(|(username, password, root, read_only)| {
+                let user = WebDavUser {
+                    password: Password::parse(password),
+                    handler: handler(
+                        fs.scoped(root, *read_only).unwrap(),
+                        ScopedLs::new(locks.clone(), root),
+                    ),
+                };
+                (username.to_string(), user)
+            })
+            .collect();
+        WebDavServer {
+            listeners: Listener::bind_all(&[ListenAddr::Tcp("127.0.0.1:0".parse().unwrap())]).unwrap(),
+            users,
+            auth_scheme: AuthScheme::Basic,
+            login_guard: LoginGuard::new(LoginGuardConfig {
+                max_failures: 3,
+                backoff: Duration::ZERO,
+                ban: Duration::from_secs(60),
+                trusted_proxies: Vec::new(),
+            }),
+            tls_config: None,
+            shutdown_timeout: Duration::from_secs(5),
+            connections: Connections::default(),
+            handler: handler(fs.boxed(), locks),
+        }
+    }
+
+    struct TestServer {
+        url: String,
+        shutdown: oneshot::Sender<()>,
+        task: tokio::task::JoinHandle<Result<()>>,
+    }
+
+    fn start(server: WebDavServer) -> TestServer {
+        let url = format!("http://{}", server.listeners[0]);
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let task = tokio::spawn(server.serve(async {
+            let _ = shutdown_rx.await;
+        }));
+        TestServer { url, shutdown, task }
+    }
+
+    impl TestServer {
+        fn request(&self, method: &str, path: &str) -> reqwest::RequestBuilder {
+            let method = reqwest::Method::from_bytes(method.as_bytes()).unwrap();
+            reqwest::Client::builder()
+                .no_proxy()
+                .build()
+                .unwrap()
+                .request(method, format!("{}{}", self.url, path))
+        }
+
+        async fn send(&self, method: &str, path: &str, user: Option<(&str, &str)>) -> StatusCode {
+            let mut req = self.request(method, path);
+            if let Some((username, password)) = user {
+                req = req.basic_auth(username, Some(password));
+            }
+            req.send().await.unwrap().status()
+        }
+
+        async fn stop(self) {
+            let _ = self.shutdown.send(());
+            self.task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_users_are_confined_to_their_root() {
+        let mock = MockQuark::start().await;
+        let alice_dir = mock.add_dir("0", "alice");
+        mock.add_file(&alice_dir, "a.txt", b"alice");
+        mock.add_file("0", "top.txt", b"top");
+        let server = start(new_server(&mock, &[
+            ("admin", "secret", "/", false),
+            ("alice", "hunter2", "/alice", false),
+            ("guest", "guest", "/", true),
+        ]));
+        let admin = Some(("admin", "secret"));
+        let alice = Some(("alice", "hunter2"));
+
+        assert_eq!(server.send("GET", "/top.txt", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.send("GET", "/top.txt", Some(("alice", "secret"))).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.send("GET", "/top.txt", Some(("nobody", ""))).await, StatusCode::UNAUTHORIZED);
+
+        let res = server.request("GET", "/a.txt").basic_auth("alice", Some("hunter2")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "alice");
+        assert_eq!(server.send("GET", "/top.txt", alice).await, StatusCode::NOT_FOUND);
+        assert_eq!(server.send("GET", "/alice/a.txt", admin).await, StatusCode::OK);
+
+        assert_eq!(server.send("GET", "/top.txt", Some(("guest", "guest"))).await, StatusCode::OK);
+        assert_eq!(server.send("DELETE", "/top.txt", Some(("guest", "guest"))).await, StatusCode::FORBIDDEN);
+        assert_eq!(mock.list("/"), ["alice", "top.txt"]);
+
+        // a lock taken by one user holds for the others
+        let res = server
+            .request("LOCK", "/a.txt")
+            .basic_auth("alice", Some("hunter2"))
+            .body(LOCK_INFO)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let token = res.headers()["lock-token"].to_str().unwrap().to_string();
+        assert_eq!(server.send("DELETE", "/alice/a.txt", admin).await, StatusCode::LOCKED);
+        let res = server
+            .request("UNLOCK", "/a.txt")
+            .basic_auth("alice", Some("hunter2"))
+            .header("Lock-Token", token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(server.send("DELETE", "/alice/a.txt", admin).await, StatusCode::NO_CONTENT);
+        assert!(mock.list("/alice").is_empty());
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_logins_are_banned() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"a");
+        let mut server = new_server(&mock, &[("admin", "secret", "/", false)]);
+        server.login_guard = LoginGuard::new(LoginGuardConfig {
+            max_failures: 3,
+            backoff: Duration::ZERO,
+            ban: Duration::from_secs(60),
+            trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+        });
+        let server = start(server);
+        let send = |client: &'static str, password: &'static str| {
+            server
+                .request("GET", "/a.txt")
+                .basic_auth("admin", Some(password))
+                .header("X-Forwarded-For", client)
+                .send()
+        };
+
+        for _ in 0..3 {
+            assert_eq!(send("198.51.100.1", "wrong").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        }
+        let res = send("198.51.100.1", "secret").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key("retry-after"));
+        // only the client is banned, not the proxy it came through
+        assert_eq!(send("198.51.100.2", "secret").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(server.send("GET", "/a.txt", Some(("admin", "secret"))).await, StatusCode::OK);
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_failed_logins_are_banned() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"a");
+        // a slow hash keeps the guesses in flight together
+        let hash = crate::auth::hash_password("secret", crate::auth::HashAlgorithm::Argon2).unwrap();
+        let server = start(new_server(&mock, &[("admin", &hash, "/", false)]));
+
+        let guesses = (0..10).map(|i| {
+            server
+                .request("GET", "/a.txt")
+                .basic_auth("admin", Some(format!("wrong{}", i)))
+                .send()
+        });
+        let statuses: Vec<_> = futures_util::future::join_all(guesses)
+            .await
+            .into_iter()
+            .map(|res| res.unwrap().status())
+            .collect();
+        let verified = statuses.iter().filter(|status| **status == StatusCode::UNAUTHORIZED).count();
+        assert_eq!(verified, 3, "{:?}", statuses);
+        assert!(statuses.iter().all(|status| [StatusCode::UNAUTHORIZED, StatusCode::TOO_MANY_REQUESTS].contains(status)));
+        assert_eq!(server.send("GET", "/a.txt", Some(("admin", "secret"))).await, StatusCode::TOO_MANY_REQUESTS);
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_digest_auth() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |data: String| format!("{:x}", Sha256::digest(data.as_bytes()));
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"a");
+        let mut server = new_server(&mock, &[("admin", "secret", "/", false)]);
+        server.auth_scheme = AuthScheme::Digest;
+        let server = start(server);
+
+        let res = server.request("GET", "/a.txt").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let challenge = res.headers()["www-authenticate"].to_str().unwrap().to_string();
+        assert!(challenge.contains("algorithm=SHA-256"));
+        let nonce = challenge.split("nonce=\"").nth(1).unwrap().split('"').next().unwrap().to_string();
+        let authorization = |password: &str, nc: u32| {
+            let ha1 = sha256(format!("admin:{}:{}", REALM, password));
+            let ha2 = sha256("GET:/a.txt".to_string());
+            let response = sha256(format!("{}:{}:{:08x}:cnonce:auth:{}", ha1, nonce, nc, ha2));
+            format!(
+                "Digest username=\"admin\", realm=\"{}\", nonce=\"{}\", uri=\"/a.txt\", algorithm=SHA-256, \
+                 response=\"{}\", qop=auth, nc={:08x}, cnonce=\"cnonce\"",
+                REALM, nonce, response, nc
+            )
+        };
+        let send = |authorization: String| server.request("GET", "/a.txt").header("Authorization", authorization).send();
+
+        assert_eq!(send(authorization("secret", 2)).await.unwrap().status(), StatusCode::OK);
+        // replayed and out of order requests are asked to retry, they are no failed logins
+        for nc in [2, 1, 1, 2] {
+            let res = send(authorization("secret", nc)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert!(res.headers()["www-authenticate"].to_str().unwrap().contains("stale=true"));
+        }
+        assert_eq!(send(authorization("secret", 3)).await.unwrap().status(), StatusCode::OK);
+
+        for nc in 4..7 {
+            let res = send(authorization("wrong", nc)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert!(!res.headers()["www-authenticate"].to_str().unwrap().contains("stale=true"));
+        }
+        assert_eq!(send(authorization("secret", 7)).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+
+        server.stop().await;
+    }
+
+    /// Start a PUT of 10 bytes and send only the first half
+    async fn start_put(server: &TestServer) -> tokio::net::TcpStream {
+        use tokio::io::AsyncWriteExt;
+
+        let mut stream = tokio::net::TcpStream::connect(server.url.trim_start_matches("http://")).await.unwrap();
+        stream
+            .write_all(b"PUT /a.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n01234")
+            .await
+            .unwrap();
+        // let the server pick up the request
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mock = MockQuark::start().await;
+        let server = start(new_server(&mock, &[]));
+        let addr = server.url.trim_start_matches("http://").to_string();
+        let mut stream = start_put(&server).await;
+
+        let _ = server.shutdown.send(());
+        let mut refused = false;
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect(&addr).await.is_err() {
+                refused = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(refused, "new connections must be refused while draining");
+        assert!(!server.task.is_finished());
+
+        // the active request still completes
+        stream.write_all(b"56789").await.unwrap();
+        let mut res = Vec::new();
+        stream.read_to_end(&mut res).await.unwrap();
+        assert!(String::from_utf8_lossy(&res).starts_with("HTTP/1.1 201"));
+        server.task.await.unwrap().unwrap();
+        assert_eq!(mock.content("/a.txt").unwrap(), b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_timeout() {
+        let mock = MockQuark::start().await;
+        let mut server = new_server(&mock, &[]);
+        server.shutdown_timeout = Duration::from_millis(200);
+        let server = start(server);
+        let _stream = start_put(&server).await;
+
+        let started = Instant::now();
+        let _ = server.shutdown.send(());
+        server.task.await.unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(mock.find("/a.txt").is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "a.txt", b"a");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webdav.sock");
+        let mut server = new_server(&mock, &[]);
+        server.listeners = Listener::bind_all(&[ListenAddr::Unix(path.clone())]).unwrap();
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let task = tokio::spawn(server.serve(async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /a.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.ends_with("\r\n\r\na"));
+
+        let _ = shutdown.send(());
+        task.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_health_paths_are_files() {
+        let mock = MockQuark::start().await;
+        mock.add_file("0", "healthz", b"mine");
+        let server = start(new_server(&mock, &[]));
+        let res = server.request("GET", "/healthz").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "mine");
+        assert_eq!(server.send("GET", "/readyz", None).await, StatusCode::NOT_FOUND);
+        server.stop().await;
+    }
+}